@@ -0,0 +1,171 @@
+//! Gives the children of split containers equal sizes.
+
+use i3_ipc::reply::{Node, NodeLayout};
+
+use crate::criteria::{self, Match};
+use crate::i3cache::{self, I3Cache};
+use crate::ipc::{self, Ipc};
+use crate::resize;
+
+/// Sizes within this fraction of each other are considered equal.
+const TOLERANCE: f64 = 0.005;
+
+/// Returns the size of each child of a split container, as a fraction of the container. i3 leaves
+/// `percent` unset until a container is resized, so the sizes fall back to the children's `rect`s.
+fn child_sizes(split: &Node, vertical: bool) -> Vec<f64> {
+    if split.nodes.iter().all(|n| n.percent.is_some()) {
+        return split.nodes.iter().map(|n| n.percent.unwrap()).collect();
+    }
+    let lengths: Vec<f64> = split
+        .nodes
+        .iter()
+        .map(|n| match vertical {
+            true => n.rect.height as f64,
+            false => n.rect.width as f64,
+        })
+        .collect();
+    let total: f64 = lengths.iter().sum();
+    match total > 0.0 {
+        true => lengths.iter().map(|l| l / total).collect(),
+        false => vec![1.0 / split.nodes.len() as f64; split.nodes.len()],
+    }
+}
+
+/// Returns the commands that give the children of `split` equal sizes.
+///
+/// `resize set` spreads the change in one child's size evenly over its siblings, so setting each
+/// child to the same size one after the other wouldn't work. Instead, each child is set to the size
+/// that makes it equal to the children before it once its siblings have been adjusted.
+fn balance_commands(split: &Node) -> Vec<String> {
+    let (dimension, vertical) = match split.layout {
+        NodeLayout::SplitH => ("width", false),
+        NodeLayout::SplitV => ("height", true),
+        _ => return vec![],
+    };
+    let count = split.nodes.len();
+    let mut sizes = child_sizes(split, vertical);
+    if count < 2
+        || sizes
+            .iter()
+            .all(|s| (s - 1.0 / count as f64).abs() < TOLERANCE)
+    {
+        return vec![];
+    }
+
+    let others = (count - 1) as f64;
+    let mut commands = vec![];
+    for k in 1..count {
+        let before = sizes[..k].iter().sum::<f64>() / k as f64;
+        let ppt = ((before * others + sizes[k]) / count as f64 * 100.0).round();
+        let shift = (sizes[k] - ppt / 100.0) / others;
+        for (i, size) in sizes.iter_mut().enumerate() {
+            *size = match i == k {
+                true => ppt / 100.0,
+                false => *size + shift,
+            };
+        }
+        commands.push(format!(
+            "[con_id={}] resize set {} {} ppt",
+            split.nodes[k].id, dimension, ppt
+        ));
+    }
+    commands
+}
+
+/// Adds the commands that balance `node`, and every split container inside it if `recursive`.
+fn balance_tree(node: &Node, recursive: bool, commands: &mut Vec<String>) {
+    commands.extend(balance_commands(node));
+    if recursive {
+        for child in node.nodes.iter() {
+            balance_tree(child, recursive, commands);
+        }
+    }
+}
+
+pub fn balance_subcmd(
+    matches: &clap::ArgMatches,
+    criteria: &[Match],
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    let recursive = matches.is_present("recursive");
+    let mut commands = vec![];
+    if criteria.is_empty() {
+        let tree = data.full_tree(conn).map_err(|e| format!("{}", e))?;
+        let focused = data.focused_node(conn)?;
+        let lineage = i3cache::lineage(tree, focused.id).ok_or("Unable to find focused node")?;
+        let (split, _, _) = resize::split_child(&lineage, None)
+            .ok_or("The focused container isn't in a split container with other containers")?;
+        balance_tree(split, recursive, &mut commands);
+    } else {
+        for workspace in criteria::selected_workspaces(conn, data, criteria)?.0 {
+            balance_tree(workspace, recursive, &mut commands);
+        }
+    }
+
+    match commands.is_empty() {
+        true => Ok(()),
+        false => ipc::run_command(conn, &commands.join("; ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{node, tree, TestIpc};
+
+    #[test]
+    fn test_balance_commands() {
+        let mut tree = tree();
+        let split = &mut tree.nodes[0].nodes[0];
+        split
+            .nodes
+            .push(serde_json::from_value(node(6, "con", Some(60), vec![])).unwrap());
+        for (child, percent) in split.nodes.iter_mut().zip(&[0.5, 0.3, 0.2]) {
+            child.percent = Some(*percent);
+        }
+        let commands = balance_commands(split);
+        assert_eq!(
+            commands,
+            vec![
+                "[con_id=5] resize set width 43 ppt",
+                "[con_id=6] resize set width 33 ppt",
+            ]
+        );
+
+        // Apply the commands the way i3 does, and check that the sizes end up equal.
+        let mut sizes: Vec<f64> = vec![0.5, 0.3, 0.2];
+        for (k, ppt) in [(1, 0.43), (2, 0.33)].iter() {
+            let shift = (sizes[*k] - ppt) / 2.0;
+            for (i, size) in sizes.iter_mut().enumerate() {
+                *size = if i == *k { *ppt } else { *size + shift };
+            }
+        }
+        assert!(sizes.iter().all(|s| (s - 1.0 / 3.0).abs() < 0.01));
+
+        for child in split.nodes.iter_mut() {
+            child.percent = Some(1.0 / 3.0);
+        }
+        assert!(balance_commands(split).is_empty());
+        split.layout = NodeLayout::Tabbed;
+        split.nodes[0].percent = Some(0.9);
+        assert!(balance_commands(split).is_empty());
+    }
+
+    #[test]
+    fn test_balance_criteria() {
+        let (conn, data) = (&mut TestIpc(tree()), &I3Cache::new());
+        let matches = clap::ArgMatches::default();
+        let balance = |conn: &mut TestIpc, input| {
+            let criteria = criteria::parse_criteria_list(input).unwrap();
+            balance_subcmd(&matches, &criteria, conn, data)
+        };
+        // Window criteria would otherwise fall through to every workspace.
+        assert_eq!(
+            balance(conn, "class=x"),
+            Err("Only output=, workspace= and empty select workspaces, found 'class=\"x\"'".into())
+        );
+        assert!(balance(conn, "floating").is_err());
+        assert!(balance(conn, "workspace=2").is_ok());
+    }
+}
@@ -1,10 +1,11 @@
 use i3_ipc::reply::NodeBorder;
-use i3_ipc::I3Stream;
 
 use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
 
+use crate::config::{self, Config};
 use crate::i3cache::I3Cache;
+use crate::ipc::{self, Ipc};
 
 #[derive(Debug, Clone, Eq)]
 pub struct Border {
@@ -62,11 +63,20 @@ pub fn parse_border(input: &str) -> Result<Border, String> {
 }
 
 pub fn validate_border(border: String) -> Result<(), String> {
+    // References to named toggle lists (`@name`) are resolved once the config file is loaded.
+    if border.starts_with('@') {
+        return Ok(());
+    }
     parse_border(border.as_str())?;
     Ok(())
 }
 
-pub fn border_subcmd(matches: &clap::ArgMatches, conn: &mut I3Stream, data: &I3Cache) {
+pub fn border_subcmd(
+    matches: &clap::ArgMatches,
+    config: &Config,
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
     //let criteria = matches.value_of("criteria").unwrap();
     let criteria = "";
 
@@ -94,26 +104,27 @@ pub fn border_subcmd(matches: &clap::ArgMatches, conn: &mut I3Stream, data: &I3C
     // match against the border type when cycling, and ignore the width. This
     // means that you won't be able to, e.g. toggle ["pixel 2" "pixel 5"
     // "pixel 10"], but you will be able to toggle ["none" "pixel 2" "normal 4"].
-    let focused = data.focused_node(conn).unwrap();
+    let focused = data.focused_node(conn)?;
     let current_state = Border {
         border: focused.border.clone(),
         width: Some(focused.current_border_width),
     };
 
     if matches.is_present("toggle") {
-        let toggle_states: Vec<Border> = matches
-            .values_of("toggle")
-            .unwrap()
-            .map(|bs| parse_border(bs).unwrap()) // already validated by clap
-            .collect();
+        let mut toggle_states: Vec<Border> = vec![];
+        for bs in matches.values_of("toggle").unwrap() {
+            match config::reference(bs) {
+                Some(name) => toggle_states.extend(config.toggle(name)?.iter().cloned()),
+                None => toggle_states.push(parse_border(bs).unwrap()), // already validated by clap
+            }
+        }
 
         // toggle states should be unique
         // Note: i3 seems to differentiate between 'none' and 'pixel 0'
         // even though they are effectively identical.
         let toggle_states_set: HashSet<Border> = toggle_states.iter().cloned().collect();
         if toggle_states_set.len() != toggle_states.len() {
-            eprintln!("Set of border states to toggle should be unique");
-            std::process::exit(1);
+            return Err("Set of border states to toggle should be unique".to_string());
         }
 
         // find index of current_state in toggle_states, otherwise use index 0
@@ -138,21 +149,19 @@ pub fn border_subcmd(matches: &clap::ArgMatches, conn: &mut I3Stream, data: &I3C
             .unwrap_or("".to_string());
 
         match next_state.border {
-            NodeBorder::None => {
-                conn.run_command(format!("[{}] border none", criteria).as_str())
-                    .unwrap();
-            }
-            NodeBorder::Normal => {
-                conn.run_command(format!("[{}] border normal {}", criteria, maybe_width).as_str())
-                    .unwrap();
-            }
-            NodeBorder::Pixel => {
-                conn.run_command(format!("[{}] border pixel {}", criteria, maybe_width).as_str())
-                    .unwrap();
-            }
+            NodeBorder::None => ipc::run_command(conn, &format!("[{}] border none", criteria)),
+            NodeBorder::Normal => ipc::run_command(
+                conn,
+                &format!("[{}] border normal {}", criteria, maybe_width),
+            ),
+            NodeBorder::Pixel => ipc::run_command(
+                conn,
+                &format!("[{}] border pixel {}", criteria, maybe_width),
+            ),
         }
     } else {
-        println!("{:?}", current_state);
+        outln!("{:?}", current_state);
+        Ok(())
     }
 }
 
@@ -0,0 +1,338 @@
+//! The oi3h config file. Toggle lists, criteria sets, icon maps and rules are defined once by
+//! name, and referenced from the command line as `@name`, e.g. `oi3h border -t @thin`.
+//!
+//! ```toml
+//! [toggles]
+//! thin = ["none", "pixel 1", "normal 1"]
+//!
+//! [criteria]
+//! browsers = ["class=(?i)firefox|chromium"]
+//!
+//! [icons.default]
+//! "(?i)firefox" = "web"
+//!
+//! [rules]
+//! default = ["on window::new where class=^mpv$ do floating enable"]
+//!
+//! [pip]
+//! size = "480x270"
+//! corner = "bottom-right"
+//! ```
+//!
+//! The icon map and rules named `default` are used by `workspace-names` and `daemon` when no
+//! `--icons` or `--rules` are given.
+
+use regex::Regex;
+use serde::de::{Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use toml::Spanned;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::border::{self, Border};
+use crate::criteria::{self, ClassMap, Match};
+use crate::float::Placement;
+use crate::pip;
+use crate::rules::{self, Rule};
+use crate::server;
+
+/// A TOML table whose entries are kept in the order they were written, so that the first matching
+/// pattern of an icon map wins just like it does in an icon map file.
+struct OrderedTable(Vec<(String, Spanned<String>)>);
+
+impl<'de> Deserialize<'de> for OrderedTable {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OrderedTableVisitor;
+
+        impl<'de> Visitor<'de> for OrderedTableVisitor {
+            type Value = OrderedTable;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a table of strings")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut entries = vec![];
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(OrderedTable(entries))
+            }
+        }
+
+        deserializer.deserialize_map(OrderedTableVisitor)
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawPip {
+    size: Option<Spanned<String>>,
+    corner: Option<Spanned<String>>,
+    margin: Option<isize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    toggles: BTreeMap<String, Vec<Spanned<String>>>,
+    criteria: BTreeMap<String, Vec<Spanned<String>>>,
+    icons: BTreeMap<String, OrderedTable>,
+    rules: BTreeMap<String, Vec<Spanned<String>>>,
+    pip: RawPip,
+}
+
+/// The size and position of windows in picture-in-picture mode.
+#[derive(Debug, Clone)]
+pub struct Pip {
+    pub width: isize,
+    pub height: isize,
+    pub corner: Placement,
+    /// Space between the window and the edges of the output.
+    pub margin: isize,
+}
+
+impl Default for Pip {
+    fn default() -> Pip {
+        Pip {
+            width: 480,
+            height: 270,
+            corner: Placement::Align(1, 1),
+            margin: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    toggles: BTreeMap<String, Vec<Border>>,
+    criteria: BTreeMap<String, Vec<Match>>,
+    icons: BTreeMap<String, ClassMap>,
+    rules: BTreeMap<String, Vec<Rule>>,
+    pip: Pip,
+}
+
+/// Returns the name referred to by an `@name` argument, or `None` if the argument isn't a
+/// reference.
+pub fn reference(arg: &str) -> Option<&str> {
+    arg.strip_prefix('@')
+}
+
+fn lookup<'a, T>(map: &'a BTreeMap<String, T>, kind: &str, name: &str) -> Result<&'a T, String> {
+    map.get(name)
+        .ok_or(format!("No {} named '@{}' in the config file", kind, name))
+}
+
+impl Config {
+    pub fn toggle(&self, name: &str) -> Result<&[Border], String> {
+        lookup(&self.toggles, "toggle list", name).map(|t| t.as_slice())
+    }
+
+    pub fn criteria(&self, name: &str) -> Result<&[Match], String> {
+        lookup(&self.criteria, "criteria set", name).map(|c| c.as_slice())
+    }
+
+    pub fn icons(&self, name: &str) -> Result<&ClassMap, String> {
+        lookup(&self.icons, "icon map", name)
+    }
+
+    pub fn default_icons(&self) -> Option<&ClassMap> {
+        self.icons.get("default")
+    }
+
+    pub fn rules(&self, name: &str) -> Result<&[Rule], String> {
+        lookup(&self.rules, "rule set", name).map(|r| r.as_slice())
+    }
+
+    pub fn default_rules(&self) -> Option<&[Rule]> {
+        self.rules.get("default").map(|r| r.as_slice())
+    }
+
+    pub fn pip(&self) -> &Pip {
+        &self.pip
+    }
+
+    /// Parses `--criteria` arguments, replacing `@name` references with the named criteria sets.
+    pub fn resolve_criteria<'a, I>(&self, args: I) -> Result<Vec<Match>, String>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut resolved = vec![];
+        for arg in args {
+            match reference(arg) {
+                Some(name) => resolved.extend(self.criteria(name)?.iter().cloned()),
+                None => resolved.extend(criteria::parse_criteria(arg)?),
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+/// Returns the line number of a byte offset into `input`.
+fn line_of(input: &str, offset: usize) -> usize {
+    input[..offset.min(input.len())].matches('\n').count() + 1
+}
+
+/// Parses each value in a list, reporting errors on the line of the offending value.
+fn parse_list<T, F>(input: &str, values: &[Spanned<String>], mut parse: F) -> Result<Vec<T>, String>
+where
+    F: FnMut(&str) -> Result<T, String>,
+{
+    values
+        .iter()
+        .map(|v| {
+            parse(v.get_ref()).map_err(|e| format!("line {}: {}", line_of(input, v.start()), e))
+        })
+        .collect()
+}
+
+/// Parses a single value, reporting errors on the line of the value.
+fn parse_value<T, F>(input: &str, value: &Spanned<String>, parse: F) -> Result<T, String>
+where
+    F: FnOnce(&str) -> Result<T, String>,
+{
+    parse(value.get_ref()).map_err(|e| format!("line {}: {}", line_of(input, value.start()), e))
+}
+
+fn parse_criteria_list(input: &str, values: &[Spanned<String>]) -> Result<Vec<Match>, String> {
+    Ok(parse_list(input, values, criteria::parse_criteria)?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+pub fn parse_config(input: &str) -> Result<Config, String> {
+    let raw: RawConfig = toml::from_str(input).map_err(|e| format!("{}", e))?;
+    let mut config = Config::default();
+
+    for (name, values) in raw.toggles.iter() {
+        let states = parse_list(input, values, border::parse_border)?;
+        config.toggles.insert(name.clone(), states);
+    }
+
+    for (name, values) in raw.criteria.iter() {
+        let criteria = parse_criteria_list(input, values)?;
+        config.criteria.insert(name.clone(), criteria);
+    }
+
+    for (name, table) in raw.icons.iter() {
+        let mut entries = vec![];
+        for (class, icon) in table.0.iter() {
+            let pattern = Regex::new(class)
+                .map_err(|e| format!("line {}: {}", line_of(input, icon.start()), e))?;
+            entries.push((pattern, icon.get_ref().clone()));
+        }
+        config.icons.insert(name.clone(), ClassMap::new(entries));
+    }
+
+    for (name, values) in raw.rules.iter() {
+        let rules = parse_list(input, values, rules::parse_rule)?;
+        config.rules.insert(name.clone(), rules);
+    }
+
+    if let Some(size) = &raw.pip.size {
+        let (width, height) = parse_value(input, size, pip::parse_size)?;
+        config.pip.width = width;
+        config.pip.height = height;
+    }
+    if let Some(corner) = &raw.pip.corner {
+        config.pip.corner = parse_value(input, corner, pip::parse_corner)?;
+    }
+    if let Some(margin) = raw.pip.margin {
+        config.pip.margin = margin;
+    }
+
+    Ok(config)
+}
+
+pub fn read_config(path: &str) -> Result<Config, String> {
+    let input = std::fs::read_to_string(server::resolve_path(path))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    parse_config(&input).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Returns `$XDG_CONFIG_HOME/oi3h/config.toml`, falling back to `~/.config/oi3h/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    server::env_var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| server::env_var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("oi3h").join("config.toml"))
+}
+
+/// Loads the config file given with `--config`, or the default config file if it exists.
+pub fn load(path: Option<&str>) -> Result<Config, String> {
+    match path {
+        Some(path) => read_config(path),
+        None => match default_path() {
+            Some(path) if path.exists() => read_config(&path.to_string_lossy()),
+            _ => Ok(Config::default()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config(
+            r#"
+            [toggles]
+            thin = ["none", "pixel 1"]
+
+            [criteria]
+            video = ["class=^mpv$", "floating"]
+
+            [icons.default]
+            "(?i)firefox" = "web"
+            "fire" = "not reached"
+            "." = "other"
+
+            [rules]
+            default = ["on window::new do border none"]
+
+            [pip]
+            size = "320x180"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.toggle("thin").unwrap().len(), 2);
+        assert_eq!(config.criteria("video").unwrap().len(), 2);
+        assert_eq!(config.default_icons().unwrap().get("Firefox"), Some("web"));
+        assert_eq!(config.icons("default").unwrap().get("xterm"), Some("other"));
+        assert_eq!(config.default_rules().unwrap().len(), 1);
+        assert_eq!(config.pip().width, 320);
+        assert_eq!(config.pip().corner, Placement::Align(1, 1));
+        assert!(config.toggle("thick").is_err());
+        assert_eq!(
+            config
+                .resolve_criteria(vec!["@video", "title=x"])
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_parse_config_errors() {
+        assert_eq!(
+            parse_config("[toggles]\nthin = [\n  \"none\",\n  \"thick\",\n]\n").unwrap_err(),
+            "line 4: 'thick': Expected one of: 'none', 'normal', 'pixel'"
+        );
+        assert!(parse_config("[rules]\ndefault = [\"on nothing do kill\"]")
+            .unwrap_err()
+            .starts_with("line 2: "));
+        assert!(parse_config("[icons.default]\n\"(\" = \"x\"")
+            .unwrap_err()
+            .starts_with("line 2: "));
+        assert_eq!(
+            parse_config("[pip]\ncorner = \"grid\"").unwrap_err(),
+            "line 2: Expected a corner or edge, found 'grid'"
+        );
+        assert!(parse_config("[unknown]").is_err());
+    }
+}
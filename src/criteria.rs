@@ -1,10 +1,16 @@
 use regex::Regex;
 
 use i3_ipc::reply::{Node, NodeType, Output, Workspace};
-use i3_ipc::I3Stream;
 
-use crate::i3cache::I3Cache;
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::expr::Expr;
+use crate::i3cache::{self, I3Cache};
+use crate::ipc::Ipc;
 use crate::search;
+use crate::server;
+use crate::urgency;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum WindowType {
@@ -45,8 +51,8 @@ pub enum Urgent {
 
 fn parse_urgent(input: &str) -> Result<Urgent, String> {
     match input.to_lowercase().as_str() {
-        "latest" | "newest" | "recent" | "last" => Ok(Urgent::Latest),
-        "oldest" | "first" => Ok(Urgent::Oldest),
+        "latest" | "newest" | "recent" => Ok(Urgent::Latest),
+        "oldest" => Ok(Urgent::Oldest),
         s => Err(format!("Unknown urgency: '{}'", s)),
     }
 }
@@ -69,6 +75,47 @@ fn parse_con_id(input: &str) -> Result<ConId, String> {
     }
 }
 
+/// Checks a layout name against the layouts i3 reports in the tree. `stacking` is accepted as
+/// well, since that's what i3's `layout` command calls it.
+fn parse_layout(input: &str) -> Result<String, String> {
+    match input.to_lowercase().as_str() {
+        "stacking" => Ok("stacked".to_string()),
+        l @ "splith"
+        | l @ "splitv"
+        | l @ "stacked"
+        | l @ "tabbed"
+        | l @ "dockarea"
+        | l @ "output" => Ok(l.to_string()),
+        s => Err(format!("Unknown layout: '{}'", s)),
+    }
+}
+
+pub fn layout_name(node: &Node) -> String {
+    serde_json::to_value(&node.layout)
+        .ok()
+        .and_then(|v| v.as_str().map(|v| v.to_string()))
+        .unwrap_or_default()
+}
+
+/// Parses the number in a comparison criterion like `depth<3` or `width>800`.
+fn parse_comparison<T, F>(
+    name: &str,
+    ordering: Ordering,
+    param: &str,
+    criterion: F,
+) -> Result<Option<Match>, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+    F: FnOnce(Ordering, T) -> Match,
+{
+    param
+        .trim()
+        .parse()
+        .map(|n| Some(criterion(ordering, n)))
+        .map_err(|e| format!("{}: {}", name, e))
+}
+
 #[derive(Debug, Clone)]
 pub enum Match {
     Class(Regex),
@@ -84,55 +131,365 @@ pub enum Match {
     ConId(ConId),
     Floating,
     Tiling,
+    // The following criteria aren't supported by i3.
+    Layout(String),
+    ParentLayout(String),
+    Fullscreen,
+    Sticky,
+    Empty,
+    Depth(Ordering, usize),
+    Pid(u32),
+    Width(Ordering, isize),
+    Height(Ordering, isize),
+    Visible,
+    FocusedOnWorkspace,
+    Not(Box<Match>),
+}
+
+fn window_type_name(window_type: WindowType) -> &'static str {
+    match window_type {
+        WindowType::Normal => "normal",
+        WindowType::Dialog => "dialog",
+        WindowType::Utility => "utility",
+        WindowType::Toolbar => "toolbar",
+        WindowType::Splash => "splash",
+        WindowType::Menu => "menu",
+        WindowType::DropdownMenu => "dropdown_menu",
+        WindowType::PopupMenu => "popup_menu",
+        WindowType::Tooltip => "tooltip",
+        WindowType::Notification => "notification",
+    }
+}
+
+fn comparison(ordering: &Ordering) -> char {
+    match ordering {
+        Ordering::Less => '<',
+        Ordering::Equal => '=',
+        Ordering::Greater => '>',
+    }
+}
+
+/// Quotes a value the way `parse_criteria_list` reads it back: only quotes are escaped, so that
+/// backslashes in regexes are shown as they were written.
+fn quote_value(input: &str) -> String {
+    format!("\"{}\"", input.replace('"', "\\\""))
+}
+
+/// Formats a criterion the way it would be written in a criteria list.
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Match::Class(r) => write!(f, "class={}", quote_value(r.as_str())),
+            Match::Instance(r) => write!(f, "instance={}", quote_value(r.as_str())),
+            Match::WindowRole(r) => write!(f, "window_role={}", quote_value(r.as_str())),
+            Match::WindowType(wt) => write!(f, "window_type={}", window_type_name(*wt)),
+            Match::Id(id) => write!(f, "id={}", id),
+            Match::Title(r) => write!(f, "title={}", quote_value(r.as_str())),
+            Match::Urgent(Urgent::Latest) => write!(f, "urgent=latest"),
+            Match::Urgent(Urgent::Oldest) => write!(f, "urgent=oldest"),
+            Match::Output(r) => write!(f, "output={}", quote_value(r.as_str())),
+            Match::Workspace(r) => write!(f, "workspace={}", quote_value(r.as_str())),
+            Match::ConMark(r) => write!(f, "con_mark={}", quote_value(r.as_str())),
+            Match::ConId(ConId::Focused) => write!(f, "con_id=__focused__"),
+            Match::ConId(ConId::Id(id)) => write!(f, "con_id={}", id),
+            Match::Floating => write!(f, "floating"),
+            Match::Tiling => write!(f, "tiling"),
+            Match::Layout(l) => write!(f, "layout={}", l),
+            Match::ParentLayout(l) => write!(f, "parent_layout={}", l),
+            Match::Fullscreen => write!(f, "fullscreen"),
+            Match::Sticky => write!(f, "sticky"),
+            Match::Empty => write!(f, "empty"),
+            Match::Depth(o, n) => write!(f, "depth{}{}", comparison(o), n),
+            Match::Pid(pid) => write!(f, "pid={}", pid),
+            Match::Width(o, n) => write!(f, "width{}{}", comparison(o), n),
+            Match::Height(o, n) => write!(f, "height{}{}", comparison(o), n),
+            Match::Visible => write!(f, "visible"),
+            Match::FocusedOnWorkspace => write!(f, "focused_on_workspace"),
+            Match::Not(m) => write!(f, "!{}", m),
+        }
+    }
+}
+
+/// An ordered list of (class pattern, value) pairs, e.g. mapping window classes to icons or to the
+/// commands that launch them. The first matching pattern wins.
+#[derive(Debug, Clone, Default)]
+pub struct ClassMap(Vec<(Regex, String)>);
+
+impl ClassMap {
+    pub fn new(entries: Vec<(Regex, String)>) -> ClassMap {
+        ClassMap(entries)
+    }
+
+    pub fn get(&self, class: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(class))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Parses a class map file. Each non-empty line that doesn't start with `#` has the form
+/// `<class regex> = <value>`.
+pub fn parse_class_map(input: &str) -> Result<ClassMap, String> {
+    let mut map = vec![];
+    for (num, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut token_split = line.splitn(2, '=');
+        let class = token_split.next().unwrap().trim();
+        let value = token_split
+            .next()
+            .ok_or(format!("line {}: expected '<class> = <value>'", num + 1))?
+            .trim();
+        let class = Regex::new(class).map_err(|e| format!("line {}: {}", num + 1, e))?;
+        map.push((class, value.to_string()));
+    }
+    Ok(ClassMap(map))
+}
+
+pub fn read_class_map(path: &str) -> Result<ClassMap, String> {
+    let input = std::fs::read_to_string(server::resolve_path(path))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    parse_class_map(&input).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Lets a bracketed list of criteria be given as a single argument (`-c '[class="Firefox"]'`)
+/// by adding the `]` argument that otherwise has to end the list of criteria.
+pub fn terminate_criteria_args(args: Vec<String>) -> Vec<String> {
+    let mut terminated = Vec::with_capacity(args.len() + 1);
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let is_criteria = arg == "-c" || arg == "--criteria";
+        terminated.push(arg);
+        if !is_criteria {
+            continue;
+        }
+        if let Some(value) = args.next() {
+            let trimmed = value.trim();
+            let bracketed = trimmed.len() > 1 && trimmed.starts_with('[') && trimmed.ends_with(']');
+            terminated.push(value);
+            if bracketed && args.peek().map_or(true, |next| next != "]") {
+                terminated.push("]".to_string());
+            }
+        }
+    }
+    terminated
+}
+
+/// Quotes a string (e.g. a workspace name) for use as an argument in an i3 command.
+pub fn i3_quote(input: &str) -> String {
+    format!("\"{}\"", input.replace('\\', "\\\\").replace('"', "\\\""))
 }
 
 pub fn validate_criteria(criteria: String) -> Result<(), String> {
+    // References to named criteria sets (`@name`) are resolved once the config file is loaded.
+    if criteria.starts_with('@') {
+        return Ok(());
+    }
     parse_criteria(criteria.as_str())?;
     Ok(())
 }
 
-pub fn parse_criteria(input: &str) -> Result<Option<Match>, String> {
-    let mut token_split: Vec<&str> = input.splitn(2, '=').collect();
-    if let Some(param) = token_split.get_mut(1) {
-        // for compatibility with i3 criteria...
-        if param.starts_with('"') && param.ends_with('"') && param.len() > 1 {
-            *param = param.get(1..(param.len() - 1)).unwrap();
+/// Parses a criteria argument. This is either a single criterion, e.g. `class=^mpv$` or
+/// `title="foo bar"`, or a whole bracketed list in i3's syntax, e.g. `[class="Firefox" floating]`.
+/// Lone `[` and `]` arguments are accepted for the `-c [ class=x ]` form.
+pub fn parse_criteria(input: &str) -> Result<Vec<Match>, String> {
+    let trimmed = input.trim();
+    match trimmed {
+        "[" | "]" => Ok(vec![]),
+        t if t.starts_with('[') => parse_criteria_list(input),
+        t => {
+            let mut token_split = t.splitn(2, '=');
+            let key = token_split.next().unwrap();
+            let value = token_split.next().map(unquote);
+            Ok(parse_criterion(key, value.as_deref())?
+                .into_iter()
+                .collect())
+        }
+    }
+}
+
+/// Strips the quotes around a value, if it is quoted, and unescapes any quotes inside it.
+fn unquote(input: &str) -> String {
+    if input.starts_with('"') && input.ends_with('"') && input.len() > 1 {
+        input[1..input.len() - 1].replace("\\\"", "\"")
+    } else {
+        input.to_string()
+    }
+}
+
+/// Reads a value at the start of `input`: either a quoted string, or everything up to the next
+/// whitespace or delimiter. Returns the value and its length in `input`. As in i3, `\"` is the
+/// only escape sequence inside quotes; other backslashes are kept as they are (they're common in
+/// regexes).
+fn parse_value(input: &str, delimiters: &[char]) -> Result<(String, usize), String> {
+    if !input.starts_with('"') {
+        let len = input
+            .find(|c: char| delimiters.contains(&c) || c.is_whitespace())
+            .unwrap_or(input.len());
+        return Ok((input[..len].to_string(), len));
+    }
+    let mut value = String::new();
+    let mut chars = input.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, i + 1)),
+            '\\' if input[i + 1..].starts_with('"') => {
+                value.push('"');
+                chars.next();
+            }
+            c => value.push(c),
         }
     }
-    let token_split = token_split;
-    match token_split[0].to_lowercase().as_str() {
-        "[" => Ok(None),
-        "]" => Ok(None), // Shouldn't need this with clap terminator, but for completeness...
-        "class" => token_split
-            .get(1)
+    Err("Unterminated quoted value".to_string())
+}
+
+pub fn skip_whitespace(input: &str, pos: usize) -> usize {
+    input[pos..]
+        .find(|c: char| !c.is_whitespace())
+        .map_or(input.len(), |len| pos + len)
+}
+
+/// Formats an error at a byte offset into `input`, giving the column it is at.
+pub fn error_at(input: &str, pos: usize, message: String) -> String {
+    format!("column {}: {}", input[..pos].chars().count() + 1, message)
+}
+
+/// Reads one criterion (`key` or `key=value`) starting at byte offset `pos` of `input`. Unquoted
+/// values end at whitespace or at one of `delimiters`. Returns the criterion and the offset just
+/// after it, or an error and the offset it refers to.
+pub fn read_criterion(
+    input: &str,
+    mut pos: usize,
+    delimiters: &[char],
+) -> Result<(Option<Match>, usize), (usize, String)> {
+    let rest = &input[pos..];
+    let key_len = rest
+        .find(|c: char| c == '=' || delimiters.contains(&c) || c.is_whitespace())
+        .unwrap_or(rest.len());
+    if key_len == 0 {
+        return Err((pos, "Expected a criterion".to_string()));
+    }
+    let (key, key_pos) = (&rest[..key_len], pos);
+    pos += key_len;
+
+    let value = match input[pos..].strip_prefix('=') {
+        Some(rest) => {
+            let (value, len) = parse_value(rest, delimiters).map_err(|e| (pos + 1, e))?;
+            pos += 1 + len;
+            Some((value, pos - len))
+        }
+        None => None,
+    };
+    match input[pos..].chars().next() {
+        Some(c) if !delimiters.contains(&c) && !c.is_whitespace() => {
+            let expected: Vec<String> = delimiters.iter().map(|d| format!("'{}'", d)).collect();
+            return Err((
+                pos,
+                match expected.is_empty() {
+                    true => "Expected whitespace".to_string(),
+                    false => format!("Expected whitespace or {}", expected.join(", ")),
+                },
+            ));
+        }
+        _ => {}
+    }
+
+    let criterion = parse_criterion(key, value.as_ref().map(|(v, _)| v.as_str())).map_err(|e| {
+        // Unknown keys are reported at the key, and anything else at the value.
+        match &value {
+            Some((_, value_pos)) if !e.starts_with("Unknown criteria") => (*value_pos, e),
+            _ => (key_pos, e),
+        }
+    })?;
+    Ok((criterion, pos))
+}
+
+/// Parses a list of criteria in i3's syntax, e.g. `[class="Firefox" title="foo \"bar\"" floating]`.
+/// The brackets are optional. Errors give the column at which the problem was found.
+pub fn parse_criteria_list(input: &str) -> Result<Vec<Match>, String> {
+    let at = |pos: usize, message: String| error_at(input, pos, message);
+
+    let mut pos = skip_whitespace(input, 0);
+    let bracketed = input[pos..].starts_with('[');
+    if bracketed {
+        pos += 1;
+    }
+
+    let mut criteria = vec![];
+    loop {
+        pos = skip_whitespace(input, pos);
+        let rest = &input[pos..];
+        if rest.is_empty() {
+            if bracketed {
+                return Err(at(pos, "Expected ']'".to_string()));
+            }
+            break;
+        }
+        if bracketed && rest.starts_with(']') {
+            pos = skip_whitespace(input, pos + 1);
+            if pos < input.len() {
+                return Err(at(pos, "Unexpected input after ']'".to_string()));
+            }
+            break;
+        }
+
+        let (criterion, end) = read_criterion(input, pos, &[']']).map_err(|(p, e)| at(p, e))?;
+        criteria.extend(criterion);
+        pos = end;
+    }
+    Ok(criteria)
+}
+
+/// Parses a single criterion from its key and value. Returns `None` for criteria that match
+/// everything.
+fn parse_criterion(key: &str, value: Option<&str>) -> Result<Option<Match>, String> {
+    if let Some(negated) = key.strip_prefix('!') {
+        return match parse_criterion(negated, value)? {
+            Some(criterion) => Ok(Some(Match::Not(Box::new(criterion)))),
+            None => Err(format!("'{}' can't be negated", negated)),
+        };
+    }
+    if let Some(pos) = key.find(|c: char| c == '<' || c == '>') {
+        if value.is_some() {
+            return Err(format!("Expected '<number>' after '{}'", &key[..=pos]));
+        }
+        let ordering = match &key[pos..=pos] {
+            "<" => Ordering::Less,
+            _ => Ordering::Greater,
+        };
+        return parse_compared(&key[..pos], ordering, &key[pos + 1..]);
+    }
+
+    match key.to_lowercase().as_str() {
+        "all" => Ok(None),
+        "class" => value
             .ok_or("class requires a parameter".to_string())
             .and_then(|param| {
                 Regex::new(param)
                     .map(|r| Some(Match::Class(r)))
                     .map_err(|e| format!("class: {}", e))
             }),
-        "instance" => token_split
-            .get(1)
+        "instance" => value
             .ok_or("instance requires a parameter".to_string())
             .and_then(|param| {
                 Regex::new(param)
                     .map(|r| Some(Match::Instance(r)))
                     .map_err(|e| format!("instance: {}", e))
             }),
-        "window_role" => token_split
-            .get(1)
+        "window_role" => value
             .ok_or("window_role requires a parameter".to_string())
             .and_then(|param| {
                 Regex::new(param)
                     .map(|r| Some(Match::WindowRole(r)))
                     .map_err(|e| format!("window_role: {}", e))
             }),
-        "window_type" => token_split
-            .get(1)
+        "window_type" => value
             .ok_or("window_type requires a parameter".to_string())
             .and_then(|param| parse_window_type(param).map(|wt| Some(Match::WindowType(wt)))),
-        "id" => token_split
-            .get(1)
+        "id" => value
             .ok_or("id requires a parameter".to_string())
             .and_then(|param| {
                 match param.starts_with("0x") {
@@ -142,93 +499,291 @@ pub fn parse_criteria(input: &str) -> Result<Option<Match>, String> {
                 .map(|parsed| Some(Match::Id(parsed)))
                 .map_err(|e| format!("id: {}", e))
             }),
-        "title" => token_split
-            .get(1)
+        "title" => value
             .ok_or("title requires a parameter".to_string())
             .and_then(|param| {
                 Regex::new(param)
                     .map(|r| Some(Match::Title(r)))
                     .map_err(|e| format!("title: {}", e))
             }),
-        "urgent" => token_split
-            .get(1)
+        "urgent" => value
             .ok_or("urgent requires a parameter".to_string())
             .and_then(|param| parse_urgent(param).map(|u| Some(Match::Urgent(u)))),
-        "output" => token_split
-            .get(1)
+        "output" => value
             .ok_or("output requires a parameter".to_string())
             .and_then(|param| {
                 Regex::new(param)
                     .map(|r| Some(Match::Output(r)))
                     .map_err(|e| format!("output: {}", e))
             }),
-        "workspace" => token_split
-            .get(1)
+        "workspace" => value
             .ok_or("workspace requires a parameter".to_string())
             .and_then(|param| {
                 Regex::new(param)
                     .map(|r| Some(Match::Workspace(r)))
                     .map_err(|e| format!("workspace: {}", e))
             }),
-        "con_mark" => token_split
-            .get(1)
+        "con_mark" => value
             .ok_or("con_mark requires a parameter".to_string())
             .and_then(|param| {
                 Regex::new(param)
                     .map(|r| Some(Match::ConMark(r)))
                     .map_err(|e| format!("con_mark: {}", e))
             }),
-        "con_id" => token_split
-            .get(1)
+        "con_id" => value
             .ok_or("con_id requires a parameter".to_string())
             .and_then(|param| parse_con_id(param).map(|ci| Some(Match::ConId(ci)))),
         "floating" => Ok(Some(Match::Floating)),
         "tiling" => Ok(Some(Match::Tiling)),
-        _ => Err(format!("Unknown criteria: '{}'", input)),
+        "layout" => value
+            .ok_or("layout requires a parameter".to_string())
+            .and_then(|param| parse_layout(param).map(|l| Some(Match::Layout(l)))),
+        "parent_layout" => value
+            .ok_or("parent_layout requires a parameter".to_string())
+            .and_then(|param| parse_layout(param).map(|l| Some(Match::ParentLayout(l)))),
+        "fullscreen" => Ok(Some(Match::Fullscreen)),
+        "sticky" => Ok(Some(Match::Sticky)),
+        "empty" => Ok(Some(Match::Empty)),
+        "pid" => value
+            .ok_or("pid requires a parameter".to_string())
+            .and_then(|param| {
+                param
+                    .parse()
+                    .map(|pid| Some(Match::Pid(pid)))
+                    .map_err(|e| format!("pid: {}", e))
+            }),
+        "visible" => Ok(Some(Match::Visible)),
+        "focused_on_workspace" => Ok(Some(Match::FocusedOnWorkspace)),
+        k @ "depth" | k @ "width" | k @ "height" => value
+            .ok_or(format!("{} requires a parameter", k))
+            .and_then(|param| parse_compared(k, Ordering::Equal, param)),
+        _ => Err(format!("Unknown criteria: '{}'", key)),
     }
 }
 
-/*
-// TODO: ugh... probably easier to write the individual ones first
-fn i3_criteria_search<'a>(
-    conn: &mut I3Stream,
-    data: &'a I3Cache,
+/// Parses a numeric criterion that is compared with `<`, `>` or `=`.
+fn parse_compared(key: &str, ordering: Ordering, param: &str) -> Result<Option<Match>, String> {
+    match key.to_lowercase().as_str() {
+        "depth" => parse_comparison(key, ordering, param, Match::Depth),
+        "width" => parse_comparison(key, ordering, param, Match::Width),
+        "height" => parse_comparison(key, ordering, param, Match::Height),
+        _ => Err(format!("Unknown criteria: '{}'", key)),
+    }
+}
+
+/// Returns true if a workspace has no windows, floating or not.
+fn is_empty_workspace(workspace: &Node) -> bool {
+    search::i3_tree_find_first(workspace, |n| n.window.is_some()).is_none()
+}
+
+/// Returns true if `node` is on a visible workspace, and isn't hidden behind another tab of a
+/// tabbed or stacked container.
+fn is_visible(conn: &mut dyn Ipc, data: &I3Cache, node: &Node) -> bool {
+    let workspace = match data.containing_workspace(conn, node.id) {
+        Some(workspace) => workspace,
+        None => return false,
+    };
+    let visible = data.workspaces(conn).map_or(false, |workspaces| {
+        workspaces
+            .iter()
+            .any(|w| w.visible && workspace.name.as_deref() == Some(w.name.as_str()))
+    });
+    if !visible {
+        return false;
+    }
+
+    let mut parent = workspace;
+    for id in i3cache::path_to(workspace, node.id).unwrap_or_default() {
+        let layout = layout_name(parent);
+        if (layout == "tabbed" || layout == "stacked") && parent.focus.get(0) != Some(&id) {
+            return false;
+        }
+        parent = match parent
+            .nodes
+            .iter()
+            .chain(parent.floating_nodes.iter())
+            .find(|n| n.id == id)
+        {
+            Some(child) => child,
+            None => return false,
+        };
+    }
+    true
+}
+
+/// Returns true if `node` is the container that would be focused by switching to its workspace.
+fn is_focused_on_workspace(conn: &mut dyn Ipc, data: &I3Cache, node: &Node) -> bool {
+    let mut focused = match data.containing_workspace(conn, node.id) {
+        Some(workspace) if workspace.id != node.id => workspace,
+        _ => return false,
+    };
+    while let Some(&id) = focused.focus.get(0) {
+        focused = match focused
+            .nodes
+            .iter()
+            .chain(focused.floating_nodes.iter())
+            .find(|n| n.id == id)
+        {
+            Some(child) => child,
+            None => break,
+        };
+    }
+    focused.id == node.id
+}
+
+/// Returns i3's fullscreen mode of a node: 0 for none, 1 for fullscreen on its output and 2 for
+/// global fullscreen.
+pub fn fullscreen_mode(node: &Node) -> u64 {
+    serde_json::to_value(&node.fullscreen_mode)
+        .ok()
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0)
+}
+
+pub fn is_floating(node: &Node) -> bool {
+    // i3 reports one of "auto_off", "auto_on", "user_off" or "user_on"
+    serde_json::to_value(&node.floating)
+        .ok()
+        .and_then(|f| f.as_str().map(|f| f.ends_with("_on")))
+        .unwrap_or(false)
+}
+
+/// Returns true if `node` satisfies a single criterion. `output=` and `workspace=` criteria are
+/// evaluated against the output and workspace that contain `node`.
+pub fn node_matches(conn: &mut dyn Ipc, data: &I3Cache, node: &Node, criterion: &Match) -> bool {
+    let properties = node.window_properties.as_ref();
+    let property_matches = |property: Option<&String>, pattern: &Regex| {
+        property.map_or(false, |p| pattern.is_match(p))
+    };
+
+    match criterion {
+        Match::Class(r) => property_matches(properties.and_then(|p| p.class.as_ref()), r),
+        Match::Instance(r) => property_matches(properties.and_then(|p| p.instance.as_ref()), r),
+        Match::WindowRole(r) => {
+            property_matches(properties.and_then(|p| p.window_role.as_ref()), r)
+        }
+        Match::WindowType(wt) => serde_json::to_value(&node.window_type)
+            .ok()
+            .and_then(|v| v.as_str().map(|v| parse_window_type(v) == Ok(*wt)))
+            .unwrap_or(false),
+        Match::Id(id) => node.window == Some(*id),
+        Match::Title(r) => property_matches(node.name.as_ref(), r),
+        Match::Urgent(urgent) => data.full_tree(conn).map_or(false, |tree| {
+            let windows = urgency::urgent_windows(tree, &data.urgency(conn));
+            let window = match urgent {
+                Urgent::Oldest => windows.first(),
+                Urgent::Latest => windows.last(),
+            };
+            window.map_or(false, |w| w.id == node.id)
+        }),
+        Match::Output(p) => data.containing_output(conn, node.id).map_or(false, |o| {
+            match_output(conn, data, OutputMatches(vec![o]), p)
+                .map_or(false, |matches| !matches.0.is_empty())
+        }),
+        Match::Workspace(p) => data
+            .containing_workspace(conn, node.id)
+            .map_or(false, |ws| {
+                match_workspace(conn, data, WorkspaceMatches(vec![ws]), p)
+                    .map_or(false, |matches| !matches.0.is_empty())
+            }),
+        Match::ConMark(r) => node.marks.0.iter().any(|m| r.is_match(m)),
+        Match::ConId(ConId::Focused) => node.focused,
+        Match::ConId(ConId::Id(id)) => node.id == *id,
+        Match::Floating => is_floating(node),
+        Match::Tiling => !is_floating(node),
+        Match::Layout(l) => &layout_name(node) == l,
+        Match::ParentLayout(l) => data
+            .parent(conn, node.id)
+            .map_or(false, |p| &layout_name(p) == l),
+        Match::Fullscreen => fullscreen_mode(node) != 0,
+        Match::Sticky => node.sticky,
+        Match::Empty => data
+            .containing_workspace(conn, node.id)
+            .map_or(false, is_empty_workspace),
+        Match::Depth(ordering, depth) => data
+            .containing_workspace(conn, node.id)
+            .and_then(|ws| i3cache::path_to(ws, node.id))
+            .map_or(false, |path| path.len().cmp(depth) == *ordering),
+        Match::Pid(pid) => node.window.and_then(|w| data.window_pid(conn, w)) == Some(*pid),
+        Match::Width(ordering, width) => node.window_rect.width.cmp(width) == *ordering,
+        Match::Height(ordering, height) => node.window_rect.height.cmp(height) == *ordering,
+        Match::Visible => is_visible(conn, data, node),
+        Match::FocusedOnWorkspace => is_focused_on_workspace(conn, data, node),
+        Match::Not(criterion) => !node_matches(conn, data, node, criterion),
+    }
+}
+
+/// Returns true if `node` satisfies every criterion.
+pub fn node_matches_all(
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+    node: &Node,
     criteria: &[Match],
-) -> Vec<&'a Node> {
-    // Not sure how I want to implement this yet, but this needs to be a narrowing search, i.e.
-    // first search is performed on the full tree, and subsequent searches are performed on this
-    // list to remove non-matching nodes.
-    let mut found = Vec::<&Node>::new();
+) -> bool {
+    criteria.iter().all(|c| node_matches(conn, data, node, c))
+}
 
-    for c in criteria.iter() {
-        match c {
-            Match::Class(r) => {}
-            Match::Instance(r) => {}
-            Match::WindowRole(r) => {}
-            Match::WindowType(wt) => {}
-            Match::Id(id) => {
-                let maybe_window_id =
-                    search::i3_tree_find_first(data.full_tree(conn).unwrap(), |n| {
-                        n.window == Some(*id)
-                    });
-                if let Some(id) = maybe_window_id {
-                    found.push(id);
-                }
+/// Finds every container that satisfies `expr`. This is a narrowing search: outputs are narrowed
+/// by the `output=` criteria that every match has to satisfy, then their workspaces by the
+/// `workspace=` criteria, and then the windows on those workspaces are checked against the whole
+/// expression. As in i3, only windows are matched, unless a `con_id=` or `con_mark=` criterion is
+/// given, in which case any container can match.
+pub fn i3_criteria_search<'a>(
+    conn: &mut dyn Ipc,
+    data: &'a I3Cache,
+    expr: &Expr,
+) -> Result<Vec<&'a Node>, String> {
+    let criteria = expr.criteria();
+    let any_con = criteria
+        .iter()
+        .any(|c| matches!(c, Match::ConId(_) | Match::ConMark(_)));
+    // `empty` selects workspaces, which never contain a window that could match it.
+    let any_workspace = criteria.iter().any(|c| matches!(c, Match::Empty));
+
+    let mut found = Vec::<&Node>::new();
+    for workspace in matched_workspaces(conn, data, &expr.required())?.0.iter() {
+        let candidates = search::i3_tree_find_all(workspace, |n| {
+            n.window.is_some()
+                || (any_con && n.id != workspace.id)
+                || (any_workspace && n.id == workspace.id)
+        });
+        for node in candidates {
+            if expr.matches(conn, data, node) {
+                found.push(node);
             }
-            Match::Title(r) => {}
-            Match::Urgent(u) => {}
-            Match::Output(p) => {}
-            Match::Workspace(r) => {}
-            Match::ConMark(Regex) => {}
-            Match::ConId(ConId) => {}
-            Match::Floating => {}
-            Match::Tiling => {}
         }
     }
 
-    found
+    // Urgent windows are listed starting with the one that became urgent last.
+    let by_urgency = expr
+        .required()
+        .iter()
+        .any(|c| matches!(c, Match::Urgent(_)));
+    if let (true, Ok(tree)) = (by_urgency, data.full_tree(conn)) {
+        let order: Vec<usize> = urgency::urgent_windows(tree, &data.urgency(conn))
+            .iter()
+            .map(|n| n.id)
+            .collect();
+        found.sort_by_key(|n| std::cmp::Reverse(order.iter().position(|&id| id == n.id)));
+    }
+    Ok(found)
+}
+
+/// Returns the containers selected by `expr`, or the focused container if `expr` has no criteria.
+pub fn selected_nodes<'a>(
+    conn: &mut dyn Ipc,
+    data: &'a I3Cache,
+    expr: &Expr,
+) -> Result<Vec<&'a Node>, String> {
+    if expr.criteria().is_empty() {
+        return Ok(vec![data.focused_node(conn)?]);
+    }
+    let nodes = i3_criteria_search(conn, data, expr)?;
+    if nodes.is_empty() {
+        return Err("No containers match the given criteria".to_string());
+    }
+    Ok(nodes)
 }
-*/
 
 #[derive(Debug)]
 pub struct OutputMatches<'a>(pub Vec<&'a Node>);
@@ -239,20 +794,20 @@ pub struct WorkspaceMatches<'a>(pub Vec<&'a Node>);
 #[derive(Debug)]
 struct NodeMatches<'a>(Vec<&'a Node>);
 
-pub fn all_outputs<'a>(conn: &mut I3Stream, data: &'a I3Cache) -> OutputMatches<'a> {
-    let root = data.full_tree(conn).unwrap();
+pub fn all_outputs<'a>(conn: &mut dyn Ipc, data: &'a I3Cache) -> Result<OutputMatches<'a>, String> {
+    let root = data.full_tree(conn).map_err(|e| format!("{}", e))?;
 
     let all_outputs = search::i3_tree_find_all(root, |n| n.node_type == NodeType::Output);
-    OutputMatches(all_outputs)
+    Ok(OutputMatches(all_outputs))
 }
 
 pub fn match_output<'a>(
-    conn: &mut I3Stream,
+    conn: &mut dyn Ipc,
     data: &'a I3Cache,
     matches: OutputMatches<'a>,
     pattern: &Regex,
-) -> OutputMatches<'a> {
-    let outputs = data.outputs(conn).unwrap();
+) -> Result<OutputMatches<'a>, String> {
+    let outputs = data.outputs(conn).map_err(|e| format!("{}", e))?;
 
     // Some(Some(&Output)): A selected output that was found
     // Some(None):          A selected output that was not found
@@ -298,7 +853,7 @@ pub fn match_output<'a>(
             .collect(),
     };
 
-    OutputMatches(new_matches)
+    Ok(OutputMatches(new_matches))
 }
 
 pub fn all_workspaces<'a>(matches: OutputMatches<'a>) -> WorkspaceMatches<'a> {
@@ -312,12 +867,22 @@ pub fn all_workspaces<'a>(matches: OutputMatches<'a>) -> WorkspaceMatches<'a> {
 }
 
 pub fn match_workspace<'a>(
-    conn: &mut I3Stream,
+    conn: &mut dyn Ipc,
     data: &'a I3Cache,
     matches: WorkspaceMatches<'a>,
     pattern: &Regex,
-) -> WorkspaceMatches<'a> {
-    let workspaces = data.workspaces(conn).unwrap();
+) -> Result<WorkspaceMatches<'a>, String> {
+    let workspaces = data.workspaces(conn).map_err(|e| format!("{}", e))?;
+
+    // All urgent workspaces, starting with the one that became urgent last.
+    if pattern.as_str() == "__urgent__" {
+        let tree = data.full_tree(conn).map_err(|e| format!("{}", e))?;
+        let urgent = urgency::urgent_workspaces(tree, &data.urgency(conn))
+            .into_iter()
+            .filter(|ws| matches.0.iter().any(|m| m.id == ws.id))
+            .collect();
+        return Ok(WorkspaceMatches(urgent));
+    }
 
     // Some(Some(&Workspace)):  A selected workspace that was found
     // Some(None):              A selected workspace that was not found
@@ -325,7 +890,6 @@ pub fn match_workspace<'a>(
     let selection: Option<Option<&Workspace>> = match pattern.as_str() {
         "__focused__" => Some(workspaces.iter().find(|w| w.focused)),
         "__visible__" => Some(workspaces.iter().find(|w| w.visible)),
-        "__urgent__" => Some(workspaces.iter().find(|w| w.urgent)),
         _ => None,
     };
 
@@ -364,5 +928,205 @@ pub fn match_workspace<'a>(
             .collect(),
     };
 
-    WorkspaceMatches(new_matches)
+    Ok(WorkspaceMatches(new_matches))
+}
+
+/// Returns the outputs selected by the `output=` criteria. i3's internal `__i3` output (which
+/// holds the scratchpad) is never selected.
+pub fn matched_outputs<'a>(
+    conn: &mut dyn Ipc,
+    data: &'a I3Cache,
+    criteria: &[Match],
+) -> Result<OutputMatches<'a>, String> {
+    let mut matches = all_outputs(conn, data)?;
+    matches
+        .0
+        .retain(|o| !o.name.as_deref().unwrap_or("").starts_with("__"));
+    for c in criteria.iter() {
+        if let Match::Output(p) = c {
+            matches = match_output(conn, data, matches, p)?;
+        }
+    }
+    Ok(matches)
+}
+
+/// Returns the workspaces on the outputs selected by the `output=` criteria that are also
+/// selected by the `workspace=` and `empty` criteria.
+pub fn matched_workspaces<'a>(
+    conn: &mut dyn Ipc,
+    data: &'a I3Cache,
+    criteria: &[Match],
+) -> Result<WorkspaceMatches<'a>, String> {
+    let mut matches = all_workspaces(matched_outputs(conn, data, criteria)?);
+    for c in criteria.iter() {
+        match c {
+            Match::Workspace(p) => matches = match_workspace(conn, data, matches, p)?,
+            Match::Empty => matches.0.retain(|ws| is_empty_workspace(ws)),
+            _ => {}
+        }
+    }
+    Ok(matches)
+}
+
+/// Like `matched_workspaces`, for commands that act on whole workspaces. Criteria that select
+/// containers rather than workspaces are an error, since they would be ignored.
+pub fn selected_workspaces<'a>(
+    conn: &mut dyn Ipc,
+    data: &'a I3Cache,
+    criteria: &[Match],
+) -> Result<WorkspaceMatches<'a>, String> {
+    let other = criteria
+        .iter()
+        .find(|c| !matches!(c, Match::Output(_) | Match::Workspace(_) | Match::Empty));
+    if let Some(criterion) = other {
+        return Err(format!(
+            "Only output=, workspace= and empty select workspaces, found '{}'",
+            criterion
+        ));
+    }
+    matched_workspaces(conn, data, criteria)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{node, TestIpc};
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_class_map() {
+        let map = parse_class_map("# comment\n\nfirefox = F\n(?i)^term = T\n").unwrap();
+        assert_eq!(map.get("firefox"), Some("F"));
+        assert_eq!(map.get("URxvt"), None);
+        assert_eq!(map.get("Terminator"), Some("T"));
+        assert!(parse_class_map("firefox").is_err());
+        assert!(parse_class_map("fire(fox = F").is_err());
+    }
+
+    #[test]
+    fn test_parse_criteria_list() {
+        let criteria =
+            parse_criteria_list(r#"[class="Firefox" title="foo \"bar\" ]"  floating]"#).unwrap();
+        assert_eq!(criteria.len(), 3);
+        assert!(matches!(&criteria[0], Match::Class(r) if r.as_str() == "Firefox"));
+        assert!(matches!(&criteria[1], Match::Title(r) if r.as_str() == r#"foo "bar" ]"#));
+        assert!(matches!(criteria[2], Match::Floating));
+
+        let criteria = parse_criteria_list(r"class=^(?i)term\d con_id=__focused__").unwrap();
+        assert!(matches!(&criteria[0], Match::Class(r) if r.as_str() == r"^(?i)term\d"));
+        assert!(matches!(criteria[1], Match::ConId(ConId::Focused)));
+        assert!(parse_criteria_list("[]").unwrap().is_empty());
+        assert!(parse_criteria_list("[all]").unwrap().is_empty());
+        assert_eq!(i3_quote(r#"a\"b\"#), r#""a\\\"b\\""#);
+
+        let error = |input| parse_criteria_list(input).unwrap_err();
+        assert_eq!(
+            error(r#"[class="Firefox"#),
+            "column 8: Unterminated quoted value"
+        );
+        assert_eq!(error("[class=x"), "column 9: Expected ']'");
+        assert_eq!(
+            error("[class=x] tiling"),
+            "column 11: Unexpected input after ']'"
+        );
+        assert_eq!(
+            error(r#"[title="a"b]"#),
+            "column 11: Expected whitespace or ']'"
+        );
+        assert_eq!(
+            error("[floating nonsense]"),
+            "column 11: Unknown criteria: 'nonsense'"
+        );
+        assert_eq!(error("[ =x]"), "column 3: Expected a criterion");
+        assert_eq!(
+            error("[urgent=never]"),
+            "column 9: Unknown urgency: 'never'"
+        );
+        assert_eq!(error("[urgent]"), "column 2: urgent requires a parameter");
+    }
+
+    #[test]
+    fn test_terminate_criteria_args() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            terminate_criteria_args(args(&["oi3h", "-c", "[class=x]", "match"])),
+            args(&["oi3h", "-c", "[class=x]", "]", "match"])
+        );
+        assert_eq!(
+            terminate_criteria_args(args(&["oi3h", "-c", "[class=x]", "]", "match"])),
+            args(&["oi3h", "-c", "[class=x]", "]", "match"])
+        );
+        assert_eq!(
+            terminate_criteria_args(args(&["oi3h", "-c", "[", "class=x", "]", "match"])),
+            args(&["oi3h", "-c", "[", "class=x", "]", "match"])
+        );
+    }
+
+    /// An output with two workspaces: 2, holding a tabbed container (3) with a wide window (4) and a
+    /// sticky one (5), and 7, which is empty.
+    fn outputs() -> Node {
+        let mut con = node(
+            3,
+            "con",
+            None,
+            vec![
+                node(4, "con", Some(40), vec![]),
+                node(5, "con", Some(50), vec![]),
+            ],
+        );
+        con["layout"] = json!("tabbed");
+        con["nodes"][0]["window_rect"]["width"] = json!(1200);
+        con["nodes"][1]["sticky"] = json!(true);
+        let output = node(
+            6,
+            "output",
+            None,
+            vec![
+                node(2, "workspace", None, vec![con]),
+                node(7, "workspace", None, vec![]),
+            ],
+        );
+        serde_json::from_value(node(1, "root", None, vec![output])).unwrap()
+    }
+
+    #[test]
+    fn test_extended_criteria() {
+        let (conn, data) = (&mut TestIpc(outputs()), &I3Cache::new());
+        let tree = data.full_tree(conn).unwrap();
+        let find = |id| search::i3_tree_find_first(tree, |n| n.id == id).unwrap();
+
+        let matches = |conn: &mut TestIpc, id, input: &str| {
+            let criteria = parse_criteria_list(input).unwrap();
+            node_matches_all(conn, data, find(id), &criteria)
+        };
+        assert!(matches(conn, 3, "layout=tabbed depth<2"));
+        assert!(matches(conn, 4, "parent_layout=tabbed depth=2 width>1000"));
+        assert!(!matches(conn, 5, "width>1000"));
+        assert!(matches(conn, 5, "sticky !focused_on_workspace"));
+        assert!(matches(conn, 4, "focused_on_workspace !sticky !fullscreen"));
+        assert!(matches(conn, 7, "empty"));
+        assert!(!matches(conn, 4, "empty"));
+
+        assert!(parse_criteria_list("depth<x").is_err());
+        assert!(parse_criteria_list("width>=3").is_err());
+        assert!(parse_criteria_list("layout=grid").is_err());
+        assert!(parse_criteria_list("!all").is_err());
+    }
+
+    #[test]
+    fn test_criteria_search() {
+        let (conn, data) = (&mut TestIpc(outputs()), &I3Cache::new());
+        let search = |conn: &mut TestIpc, input: &str| {
+            let expr = Expr::from_criteria(&parse_criteria_list(input).unwrap());
+            i3_criteria_search(conn, data, &expr)
+                .unwrap()
+                .iter()
+                .map(|n| n.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(search(conn, "empty"), vec![7]);
+        assert_eq!(search(conn, "sticky"), vec![5]);
+        assert_eq!(search(conn, "con_id=3"), vec![3]);
+        assert_eq!(search(conn, "!empty !sticky"), vec![4]);
+    }
 }
@@ -0,0 +1,240 @@
+//! Compares two i3 trees, matching containers by id.
+
+use i3_ipc::reply::{Node, Rect};
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::i3cache::I3Cache;
+use crate::ipc::Ipc;
+use crate::search::TreeIter;
+use crate::server;
+
+/// One difference between two trees.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added {
+        id: usize,
+        description: String,
+        parent: Option<usize>,
+    },
+    Removed {
+        id: usize,
+        description: String,
+    },
+    Moved {
+        id: usize,
+        description: String,
+        from: Option<usize>,
+        to: Option<usize>,
+    },
+    Property {
+        id: usize,
+        description: String,
+        property: &'static str,
+        old: String,
+        new: String,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Added {
+                id,
+                description,
+                parent,
+            } => write!(f, "+ {} {} in {}", id, description, format_id(*parent)),
+            Change::Removed { id, description } => write!(f, "- {} {}", id, description),
+            Change::Moved {
+                id,
+                description,
+                from,
+                to,
+            } => write!(
+                f,
+                "> {} {} moved from {} to {}",
+                id,
+                description,
+                format_id(*from),
+                format_id(*to)
+            ),
+            Change::Property {
+                id,
+                description,
+                property,
+                old,
+                new,
+            } => write!(
+                f,
+                "~ {} {} {}: {} -> {}",
+                id, description, property, old, new
+            ),
+        }
+    }
+}
+
+fn format_id(id: Option<usize>) -> String {
+    id.map_or("nothing".to_string(), |id| id.to_string())
+}
+
+/// Describes a node by its type and name, e.g. `con "Firefox"`.
+fn describe(node: &Node) -> String {
+    format!(
+        "{} {:?}",
+        serialized(&node.node_type),
+        node.name.as_deref().unwrap_or("")
+    )
+}
+
+/// Returns the name i3 uses for an enum value, e.g. `splith` for a layout.
+fn serialized<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        Ok(other) => other.to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+fn format_rect(rect: &Rect) -> String {
+    format!("{}x{}+{}+{}", rect.width, rect.height, rect.x, rect.y)
+}
+
+/// The properties that are compared between matching nodes.
+fn properties(node: &Node) -> Vec<(&'static str, String)> {
+    vec![
+        ("name", format!("{:?}", node.name.as_deref().unwrap_or(""))),
+        ("layout", serialized(&node.layout)),
+        ("rect", format_rect(&node.rect)),
+        ("border", serialized(&node.border)),
+        ("floating", serialized(&node.floating)),
+        ("marks", format!("{:?}", node.marks.0)),
+        ("urgent", node.urgent.to_string()),
+        ("focused", node.focused.to_string()),
+        ("focus", format!("{:?}", node.focus)),
+    ]
+}
+
+/// Maps the id of every node in a tree to the node and the id of its parent.
+fn index(root: &Node) -> HashMap<usize, (&Node, Option<usize>)> {
+    let mut index = HashMap::new();
+    index.insert(root.id, (root, None));
+    for node in TreeIter::from(root) {
+        for child in node.nodes.iter().chain(node.floating_nodes.iter()) {
+            index.insert(child.id, (child, Some(node.id)));
+        }
+    }
+    index
+}
+
+/// Lists the containers that were added, removed or moved to a different parent between `old`
+/// and `new`, and the properties that changed on the others. Changes are listed in tree order.
+pub fn diff_trees(old: &Node, new: &Node) -> Vec<Change> {
+    let old_index = index(old);
+    let new_index = index(new);
+
+    let mut changes = vec![];
+    for node in TreeIter::from(new) {
+        let description = describe(node);
+        let parent = new_index[&node.id].1;
+        let (old_node, old_parent) = match old_index.get(&node.id) {
+            Some(&entry) => entry,
+            None => {
+                changes.push(Change::Added {
+                    id: node.id,
+                    description,
+                    parent,
+                });
+                continue;
+            }
+        };
+
+        if old_parent != parent {
+            changes.push(Change::Moved {
+                id: node.id,
+                description: description.clone(),
+                from: old_parent,
+                to: parent,
+            });
+        }
+        for ((property, old_value), (_, new_value)) in
+            properties(old_node).into_iter().zip(properties(node))
+        {
+            if old_value != new_value {
+                changes.push(Change::Property {
+                    id: node.id,
+                    description: description.clone(),
+                    property,
+                    old: old_value,
+                    new: new_value,
+                });
+            }
+        }
+    }
+
+    for node in TreeIter::from(old) {
+        if !new_index.contains_key(&node.id) {
+            changes.push(Change::Removed {
+                id: node.id,
+                description: describe(node),
+            });
+        }
+    }
+    changes
+}
+
+/// Reads a tree saved with e.g. `i3-msg -t get_tree`.
+pub fn read_tree(path: &str) -> Result<Node, String> {
+    let input = std::fs::read_to_string(server::resolve_path(path))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    serde_json::from_str(&input).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Prints the differences between two saved trees, or between a saved tree and the current one.
+pub fn tree_diff(files: &[&str], conn: &mut dyn Ipc, data: &I3Cache) -> Result<(), String> {
+    let old = read_tree(files[0])?;
+    let changes = match files.get(1) {
+        Some(path) => diff_trees(&old, &read_tree(path)?),
+        None => diff_trees(&old, data.full_tree(conn).map_err(|e| format!("{}", e))?),
+    };
+    for change in changes.iter() {
+        outln!("{}", change);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{node, tree};
+
+    #[test]
+    fn test_diff_trees() {
+        let old = tree();
+        assert!(diff_trees(&old, &old).is_empty());
+
+        let mut new = old.clone();
+        let moved = new.nodes[0].nodes[0].nodes.remove(1);
+        new.nodes[0].nodes.push(moved);
+        new.nodes[0].nodes[0].nodes.remove(0);
+        new.nodes[0].nodes[0]
+            .nodes
+            .push(serde_json::from_value(node(6, "con", Some(60), vec![])).unwrap());
+        new.nodes[0].nodes[0].layout = serde_json::from_value(serde_json::json!("tabbed")).unwrap();
+
+        let changes: Vec<String> = diff_trees(&old, &new)
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                "~ 3 con \"\" layout: splith -> tabbed",
+                "+ 6 con \"\" in 3",
+                "> 5 con \"\" moved from 3 to 2",
+                "- 4 con \"\"",
+            ]
+        );
+    }
+}
@@ -0,0 +1,305 @@
+//! Boolean expressions over criteria, e.g. `any(class=firefox, class=chromium) and not floating`.
+//!
+//! ```text
+//! expr    := and ("or" and)*
+//! and     := unary (["and"] unary)*
+//! unary   := "not" unary | primary
+//! primary := ("any" | "all") "(" [expr ("," expr)*] ")" | "(" expr ")" | criterion
+//! ```
+//!
+//! Criteria use the same syntax as in a criteria list, except that unquoted values also end at
+//! `,`, `(` and `)`. Criteria written next to each other are combined with `and`, so a criteria
+//! list is also an expression.
+
+use i3_ipc::reply::Node;
+
+use std::fmt;
+
+use crate::criteria::{self, Match};
+use crate::i3cache::I3Cache;
+use crate::ipc::Ipc;
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Criterion(Match),
+    All(Vec<Expr>),
+    Any(Vec<Expr>),
+    Not(Box<Expr>),
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |f: &mut fmt::Formatter, name: &str, exprs: &[Expr]| {
+            let exprs: Vec<String> = exprs.iter().map(|e| e.to_string()).collect();
+            write!(f, "{}({})", name, exprs.join(", "))
+        };
+        match self {
+            Expr::Criterion(m) => write!(f, "{}", m),
+            Expr::All(exprs) => list(f, "all", exprs),
+            Expr::Any(exprs) => list(f, "any", exprs),
+            Expr::Not(expr) => write!(f, "not {}", expr),
+        }
+    }
+}
+
+impl Expr {
+    /// Combines a list of criteria, which must all be satisfied.
+    pub fn from_criteria(criteria: &[Match]) -> Expr {
+        Expr::All(criteria.iter().cloned().map(Expr::Criterion).collect())
+    }
+
+    /// Returns an expression that requires both `self` and `other` to be satisfied.
+    pub fn and(self, other: Expr) -> Expr {
+        let mut exprs = match self {
+            Expr::All(exprs) => exprs,
+            expr => vec![expr],
+        };
+        match other {
+            Expr::All(other) => exprs.extend(other),
+            other => exprs.push(other),
+        }
+        Expr::All(exprs)
+    }
+
+    /// Returns every criterion in the expression.
+    pub fn criteria(&self) -> Vec<&Match> {
+        match self {
+            Expr::Criterion(m) => vec![m],
+            Expr::All(exprs) | Expr::Any(exprs) => {
+                exprs.iter().flat_map(|e| e.criteria()).collect()
+            }
+            Expr::Not(expr) => expr.criteria(),
+        }
+    }
+
+    /// Returns the criteria that every match has to satisfy, i.e. the ones that aren't inside an
+    /// `any` or a `not`. These can be used to narrow down a search.
+    pub fn required(&self) -> Vec<Match> {
+        match self {
+            Expr::Criterion(m) => vec![m.clone()],
+            Expr::All(exprs) => exprs.iter().flat_map(|e| e.required()).collect(),
+            Expr::Any(_) | Expr::Not(_) => vec![],
+        }
+    }
+
+    pub fn matches(&self, conn: &mut dyn Ipc, data: &I3Cache, node: &Node) -> bool {
+        match self {
+            Expr::Criterion(m) => criteria::node_matches(conn, data, node, m),
+            Expr::All(exprs) => exprs.iter().all(|e| e.matches(conn, data, node)),
+            Expr::Any(exprs) => exprs.iter().any(|e| e.matches(conn, data, node)),
+            Expr::Not(expr) => !expr.matches(conn, data, node),
+        }
+    }
+
+    /// Evaluates every sub-expression against `node`, returning one line per sub-expression that
+    /// says whether it matched. Sub-expressions are indented below the expression they are part
+    /// of.
+    pub fn explain(&self, conn: &mut dyn Ipc, data: &I3Cache, node: &Node) -> Vec<String> {
+        let mut lines = vec![];
+        self.explain_into(conn, data, node, 0, &mut lines);
+        lines
+    }
+
+    fn explain_into(
+        &self,
+        conn: &mut dyn Ipc,
+        data: &I3Cache,
+        node: &Node,
+        depth: usize,
+        lines: &mut Vec<String>,
+    ) -> bool {
+        let index = lines.len();
+        lines.push(String::new());
+        let mut explain_all = |exprs: &[Expr]| -> Vec<bool> {
+            exprs
+                .iter()
+                .map(|e| e.explain_into(conn, data, node, depth + 1, lines))
+                .collect()
+        };
+        let (label, matched) = match self {
+            Expr::Criterion(m) => (m.to_string(), criteria::node_matches(conn, data, node, m)),
+            Expr::All(exprs) => ("all".to_string(), explain_all(exprs).iter().all(|&m| m)),
+            Expr::Any(exprs) => ("any".to_string(), explain_all(exprs).iter().any(|&m| m)),
+            Expr::Not(expr) => (
+                "not".to_string(),
+                !expr.explain_into(conn, data, node, depth + 1, lines),
+            ),
+        };
+        lines[index] = format!(
+            "{}{} {}",
+            "  ".repeat(depth),
+            if matched { "✓" } else { "✗" },
+            label
+        );
+        matched
+    }
+}
+
+/// Characters that end an unquoted value inside an expression.
+const DELIMITERS: &[char] = &[',', '(', ')'];
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, pos: usize, message: &str) -> String {
+        criteria::error_at(self.input, pos, message.to_string())
+    }
+
+    fn peek_char(&mut self) -> Option<char> {
+        self.pos = criteria::skip_whitespace(self.input, self.pos);
+        self.input[self.pos..].chars().next()
+    }
+
+    /// Returns the word at the current position, which may be a keyword.
+    fn peek_word(&mut self) -> &'a str {
+        self.pos = criteria::skip_whitespace(self.input, self.pos);
+        let rest = &self.input[self.pos..];
+        let len = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || DELIMITERS.contains(&c))
+            .unwrap_or(rest.len());
+        &rest[..len]
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut exprs = vec![self.and()?];
+        while self.peek_word() == "or" {
+            self.pos += 2;
+            exprs.push(self.and()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => Expr::Any(exprs),
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut exprs = vec![self.unary()?];
+        loop {
+            match self.peek_word() {
+                "and" => self.pos += 3,
+                "or" => break,
+                _ => match self.peek_char() {
+                    None | Some(',') | Some(')') => break,
+                    _ => {}
+                },
+            }
+            exprs.push(self.unary()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => Expr::All(exprs),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.peek_word() == "not" {
+            self.pos += 3;
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let word = self.peek_word();
+        let after_word = criteria::skip_whitespace(self.input, self.pos + word.len());
+        if (word == "any" || word == "all") && self.input[after_word..].starts_with('(') {
+            self.pos = after_word + 1;
+            let mut exprs = vec![];
+            if self.peek_char() != Some(')') {
+                loop {
+                    exprs.push(self.or()?);
+                    match self.peek_char() {
+                        Some(',') => self.pos += 1,
+                        Some(')') => break,
+                        _ => return Err(self.error(self.pos, "Expected ',' or ')'")),
+                    }
+                }
+            }
+            self.pos += 1;
+            return Ok(match word {
+                "any" => Expr::Any(exprs),
+                _ => Expr::All(exprs),
+            });
+        }
+
+        match self.peek_char() {
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.or()?;
+                if self.peek_char() != Some(')') {
+                    return Err(self.error(self.pos, "Expected ')'"));
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            None => Err(self.error(self.pos, "Expected a criterion")),
+            Some(_) => {
+                let (criterion, end) = criteria::read_criterion(self.input, self.pos, DELIMITERS)
+                    .map_err(|(pos, e)| self.error(pos, &e))?;
+                self.pos = end;
+                // `all` on its own matches everything.
+                Ok(criterion.map_or(Expr::All(vec![]), Expr::Criterion))
+            }
+        }
+    }
+}
+
+pub fn parse_expr(input: &str) -> Result<Expr, String> {
+    let mut parser = Parser { input, pos: 0 };
+    let expr = parser.or()?;
+    match parser.peek_char() {
+        None => Ok(expr),
+        Some(c) => Err(parser.error(parser.pos, &format!("Unexpected '{}'", c))),
+    }
+}
+
+pub fn validate_expr(input: String) -> Result<(), String> {
+    parse_expr(&input).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_expr() {
+        let expr = parse_expr("any(class=firefox, class=\"chromium\") and not floating").unwrap();
+        assert_eq!(
+            expr.to_string(),
+            r#"all(any(class="firefox", class="chromium"), not floating)"#
+        );
+        assert_eq!(expr.required().len(), 0);
+        assert_eq!(expr.criteria().len(), 3);
+
+        let expr = parse_expr("output=__focused__ (title=a or not tiling) sticky").unwrap();
+        assert_eq!(
+            expr.to_string(),
+            r#"all(output="__focused__", any(title="a", not tiling), sticky)"#
+        );
+        assert_eq!(expr.required().len(), 2);
+
+        assert_eq!(
+            parse_expr("a=b").unwrap_err(),
+            "column 1: Unknown criteria: 'a'"
+        );
+        assert_eq!(
+            parse_expr("any(class=a class=b").unwrap_err(),
+            "column 20: Expected ',' or ')'"
+        );
+        assert_eq!(
+            parse_expr("(floating").unwrap_err(),
+            "column 10: Expected ')'"
+        );
+        assert_eq!(
+            parse_expr("floating)").unwrap_err(),
+            "column 9: Unexpected ')'"
+        );
+        assert_eq!(
+            parse_expr("floating and").unwrap_err(),
+            "column 13: Expected a criterion"
+        );
+    }
+}
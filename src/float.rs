@@ -0,0 +1,265 @@
+//! Placement of floating windows. Positions are computed from the `rect` of each window's
+//! workspace, which already leaves out bars and outer gaps.
+
+use i3_ipc::reply::{Node, Rect};
+
+use crate::criteria;
+use crate::expr::Expr;
+use crate::i3cache::I3Cache;
+use crate::ipc::{self, Ipc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Aligns the window to an edge or corner of the workspace (or its center). Each coordinate is
+    /// -1 (left/top), 0 (center) or 1 (right/bottom).
+    Align(i8, i8),
+    /// Splits the workspace into a grid of `columns` x `rows` cells, and fills a cell. Without a
+    /// cell number, the windows fill consecutive cells.
+    Grid {
+        columns: usize,
+        rows: usize,
+        cell: Option<usize>,
+    },
+    /// Like `Grid`, with as many cells as there are windows, arranged as close to a square as
+    /// possible.
+    AutoGrid,
+}
+
+fn parse_grid_size(input: &str) -> Option<(usize, usize)> {
+    let mut split = input.splitn(2, 'x');
+    let columns = split.next()?.parse().ok()?;
+    let rows = split.next()?.parse().ok()?;
+    match columns > 0 && rows > 0 {
+        true => Some((columns, rows)),
+        false => None,
+    }
+}
+
+pub fn parse_placement(input: &str) -> Result<Placement, String> {
+    let (x, y) = match input {
+        "center" => (0, 0),
+        "top-left" => (-1, -1),
+        "top" => (0, -1),
+        "top-right" => (1, -1),
+        "left" => (-1, 0),
+        "right" => (1, 0),
+        "bottom-left" => (-1, 1),
+        "bottom" => (0, 1),
+        "bottom-right" => (1, 1),
+        "grid" => return Ok(Placement::AutoGrid),
+        grid if grid.starts_with("grid ") => {
+            let spec = grid[5..].trim();
+            let mut split = spec.splitn(2, ':');
+            let (columns, rows) = parse_grid_size(split.next().unwrap())
+                .ok_or(format!("Expected 'grid NxM[:i]', found '{}'", input))?;
+            let cell = match split.next() {
+                Some(cell) => match cell.parse::<usize>() {
+                    Ok(cell) if cell >= 1 && cell <= columns * rows => Some(cell - 1),
+                    _ => {
+                        return Err(format!(
+                            "Grid cell must be between 1 and {}, found '{}'",
+                            columns * rows,
+                            cell
+                        ))
+                    }
+                },
+                None => None,
+            };
+            return Ok(Placement::Grid {
+                columns,
+                rows,
+                cell,
+            });
+        }
+        _ => return Err(format!("Unknown placement: '{}'", input)),
+    };
+    Ok(Placement::Align(x, y))
+}
+
+pub fn validate_placement(input: String) -> Result<(), String> {
+    parse_placement(&input).map(|_| ())
+}
+
+/// Aligns a window of the given size within `area`.
+fn align(area: &Rect, width: isize, height: isize, x: i8, y: i8) -> Rect {
+    let offset = |start: isize, available: isize, size: isize, position: i8| match position {
+        -1 => start,
+        0 => start + (available - size) / 2,
+        _ => start + available - size,
+    };
+    Rect {
+        x: offset(area.x, area.width, width, x),
+        y: offset(area.y, area.height, height, y),
+        width,
+        height,
+    }
+}
+
+/// Returns cell `cell` (counting left to right, then top to bottom) of a grid over `area`.
+fn grid_cell(area: &Rect, columns: usize, rows: usize, cell: usize) -> Rect {
+    let (column, row) = (
+        (cell % columns) as isize,
+        ((cell / columns) % rows) as isize,
+    );
+    let (columns, rows) = (columns as isize, rows as isize);
+    // Cells take up the whole area, so rounding is absorbed by the last row and column.
+    let x = area.x + area.width * column / columns;
+    let y = area.y + area.height * row / rows;
+    Rect {
+        x,
+        y,
+        width: area.x + area.width * (column + 1) / columns - x,
+        height: area.y + area.height * (row + 1) / rows - y,
+    }
+}
+
+/// Returns the number of columns and rows of the most square grid with at least `count` cells.
+fn auto_grid(count: usize) -> (usize, usize) {
+    let columns = (1..).find(|c| c * c >= count).unwrap();
+    let rows = (1..).find(|r| r * columns >= count).unwrap();
+    (columns, rows)
+}
+
+/// Computes the geometry of window `index` out of `count` windows being placed together.
+pub fn place(placement: Placement, area: &Rect, window: &Rect, index: usize, count: usize) -> Rect {
+    match placement {
+        Placement::Align(x, y) => align(area, window.width, window.height, x, y),
+        Placement::Grid {
+            columns,
+            rows,
+            cell,
+        } => grid_cell(area, columns, rows, cell.unwrap_or(index)),
+        Placement::AutoGrid => {
+            let (columns, rows) = auto_grid(count);
+            grid_cell(area, columns, rows, index)
+        }
+    }
+}
+
+/// Computes the geometry of every node, as (container id, rect) pairs. Nodes are placed together
+/// with the other selected nodes on the same workspace, so that each workspace gets its own grid.
+fn placements(
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+    placement: Placement,
+    nodes: &[&Node],
+) -> Result<Vec<(usize, Rect)>, String> {
+    let mut workspaces: Vec<(&Node, Vec<&Node>)> = vec![];
+    for &node in nodes {
+        let workspace = data
+            .containing_workspace(conn, node.id)
+            .ok_or(format!("Container {} isn't on a workspace", node.id))?;
+        match workspaces.iter_mut().find(|(ws, _)| ws.id == workspace.id) {
+            Some((_, group)) => group.push(node),
+            None => workspaces.push((workspace, vec![node])),
+        }
+    }
+
+    let mut placed = vec![];
+    for (workspace, group) in workspaces.iter() {
+        for (index, node) in group.iter().enumerate() {
+            let rect = place(placement, &workspace.rect, &node.rect, index, group.len());
+            placed.push((node.id, rect));
+        }
+    }
+    Ok(placed)
+}
+
+pub fn float_subcmd(
+    matches: &clap::ArgMatches,
+    expr: &Expr,
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    let placement = parse_placement(matches.value_of("place").unwrap())?;
+    let nodes = criteria::selected_nodes(conn, data, expr)?;
+
+    let mut commands = vec![];
+    for (id, rect) in placements(conn, data, placement, &nodes)? {
+        commands.push(format!(
+            "[con_id={}] floating enable, resize set {} px {} px, move position {} px {} px",
+            id, rect.width, rect.height, rect.x, rect.y
+        ));
+    }
+    ipc::run_command(conn, &commands.join("; "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search;
+    use crate::testutil::{node, TestIpc};
+
+    /// Two workspaces side by side, 2 (windows 3 and 4) and 5 (windows 6 and 7).
+    fn workspaces() -> Node {
+        let workspace = |id, x, windows: Vec<usize>| {
+            let windows = windows
+                .into_iter()
+                .map(|id| node(id, "con", Some(id as u32 * 10), vec![]))
+                .collect();
+            let mut workspace = node(id, "workspace", None, windows);
+            workspace["rect"] = serde_json::json!({"x": x, "y": 0, "width": 1000, "height": 500});
+            workspace
+        };
+        let output = node(
+            10,
+            "output",
+            None,
+            vec![workspace(2, 0, vec![3, 4]), workspace(5, 1000, vec![6, 7])],
+        );
+        serde_json::from_value(node(1, "root", None, vec![output])).unwrap()
+    }
+
+    fn rect(x: isize, y: isize, width: isize, height: isize) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_place() {
+        let area = rect(0, 20, 1920, 1060);
+        let window = rect(100, 100, 800, 600);
+        let place_one = |input| place(parse_placement(input).unwrap(), &area, &window, 0, 1);
+        assert_eq!(place_one("center"), rect(560, 250, 800, 600));
+        assert_eq!(place_one("top-left"), rect(0, 20, 800, 600));
+        assert_eq!(place_one("bottom-right"), rect(1120, 480, 800, 600));
+        assert_eq!(place_one("grid 3x2:5"), rect(640, 550, 640, 530));
+
+        // Three windows tile a 2x2 grid, and the last cell absorbs any rounding.
+        let placement = parse_placement("grid").unwrap();
+        assert_eq!(
+            place(placement, &area, &window, 2, 3),
+            rect(0, 550, 960, 530)
+        );
+        let area = rect(0, 0, 100, 100);
+        let placement = parse_placement("grid 3x1").unwrap();
+        assert_eq!(place(placement, &area, &window, 2, 3), rect(66, 0, 34, 100));
+
+        assert!(parse_placement("middle").is_err());
+        assert!(parse_placement("grid 0x2").is_err());
+        assert!(parse_placement("grid 2x2:5").is_err());
+    }
+
+    #[test]
+    fn test_placements() {
+        let (conn, data) = (&mut TestIpc(workspaces()), &I3Cache::new());
+        let tree = data.full_tree(conn).unwrap();
+        let nodes = search::i3_tree_find_all(tree, |n| n.window.is_some());
+        let placement = parse_placement("grid").unwrap();
+
+        // Each workspace splits into its own 2x1 grid.
+        assert_eq!(
+            placements(conn, data, placement, &nodes).unwrap(),
+            vec![
+                (3, rect(0, 0, 500, 500)),
+                (4, rect(500, 0, 500, 500)),
+                (6, rect(1000, 0, 500, 500)),
+                (7, rect(1500, 0, 500, 500)),
+            ]
+        );
+    }
+}
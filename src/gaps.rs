@@ -0,0 +1,262 @@
+//! Per-workspace gaps. i3 only changes the gaps of the focused workspace, so other workspaces are
+//! focused briefly while their gaps are changed.
+//!
+//! i3 doesn't report gaps directly, so the current gaps are measured from the tree: outer gaps are
+//! the space between a workspace and the area of its output, and inner gaps the space between a
+//! workspace and its tiled windows. Workspaces without tiled windows fall back to the gaps oi3h
+//! last chose for them, which are kept in a state file.
+
+use serde::{Deserialize, Serialize};
+
+use i3_ipc::reply::Node;
+
+use std::collections::BTreeMap;
+
+use crate::criteria::{self, Match};
+use crate::i3cache::{self, I3Cache};
+use crate::ipc::{self, Ipc};
+use crate::server;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Gaps {
+    inner: isize,
+    outer: isize,
+}
+
+/// Parses `<inner>/<outer>`, or a single number for both.
+pub fn parse_gaps(input: &str) -> Result<Gaps, String> {
+    let parse = |s: &str| {
+        s.trim()
+            .parse::<isize>()
+            .ok()
+            .filter(|&px| px >= 0)
+            .ok_or(format!(
+                "Expected '<px>' or '<inner px>/<outer px>', found '{}'",
+                input
+            ))
+    };
+    match input.find('/') {
+        Some(pos) => Ok(Gaps {
+            inner: parse(&input[..pos])?,
+            outer: parse(&input[pos + 1..])?,
+        }),
+        None => {
+            let px = parse(input)?;
+            Ok(Gaps {
+                inner: px,
+                outer: px,
+            })
+        }
+    }
+}
+
+pub fn validate_gaps(input: String) -> Result<(), String> {
+    parse_gaps(&input).map(|_| ())
+}
+
+/// Maps workspaces to the gaps last chosen for them with `--toggle`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct GapsState(BTreeMap<String, Gaps>);
+
+/// The state file, `$XDG_RUNTIME_DIR/oi3h-gaps.json`.
+const STATE: &str = "-gaps.json";
+
+/// Returns the key of a workspace in the state file. Numbered workspaces are stored by number,
+/// so that renaming them (e.g. with `workspace-names`) doesn't lose their gaps.
+fn state_key(workspace: &Node) -> String {
+    match workspace.num {
+        Some(num) if num >= 0 => num.to_string(),
+        _ => workspace.name.clone().unwrap_or_default(),
+    }
+}
+
+/// Returns the tiled windows of a container that are visible. Only the focused child of a tabbed
+/// or stacked container is visible, and floating windows aren't part of `nodes`.
+fn tiled_windows(node: &Node) -> Vec<&Node> {
+    if node.window.is_some() {
+        return vec![node];
+    }
+    match criteria::layout_name(node).as_str() {
+        "tabbed" | "stacked" => node
+            .focus
+            .iter()
+            .find_map(|&id| node.nodes.iter().find(|n| n.id == id))
+            .map_or(vec![], tiled_windows),
+        _ => node.nodes.iter().flat_map(tiled_windows).collect(),
+    }
+}
+
+/// Measures the gaps of a workspace from the geometry of the workspace, its parent (the area of
+/// the output) and its tiled windows.
+fn measured_gaps(content: &Node, workspace: &Node) -> Option<Gaps> {
+    let inner = tiled_windows(workspace)
+        .iter()
+        .map(|w| w.rect.x - workspace.rect.x)
+        .min()?;
+    Some(Gaps {
+        inner,
+        outer: workspace.rect.x - content.rect.x,
+    })
+}
+
+/// Returns the gaps that come after `current` in `presets`, or the first preset if `current`
+/// isn't one of them.
+fn next_gaps(presets: &[Gaps], current: Option<Gaps>) -> Gaps {
+    match presets.iter().position(|&g| Some(g) == current) {
+        Some(pos) => presets[(pos + 1) % presets.len()],
+        None => presets[0],
+    }
+}
+
+pub fn gaps_subcmd(
+    matches: &clap::ArgMatches,
+    criteria: &[Match],
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    let tree = data.full_tree(conn).map_err(|e| format!("{}", e))?;
+    let focused = data.focused_workspace(conn)?;
+    let workspaces = match criteria.is_empty() {
+        true => vec![focused],
+        false => criteria::selected_workspaces(conn, data, criteria)?.0,
+    };
+
+    let mut state: GapsState = server::load_state(STATE);
+    let mut changes = vec![];
+    for workspace in workspaces {
+        let measured = i3cache::lineage(tree, workspace.id)
+            .and_then(|lineage| lineage.iter().rev().nth(1).copied())
+            .and_then(|content| measured_gaps(content, workspace));
+        let key = state_key(workspace);
+
+        if let Some(presets) = matches.values_of("toggle") {
+            let presets: Vec<Gaps> = presets
+                .map(parse_gaps)
+                .collect::<Result<Vec<_>, String>>()?;
+            let next = next_gaps(&presets, measured.or_else(|| state.0.get(&key).copied()));
+            state.0.insert(key, next);
+            changes.push((workspace, next));
+        } else {
+            // --smart
+            let chosen = match tiled_windows(workspace).len() {
+                0 => None,
+                1 => Some(Gaps { inner: 0, outer: 0 }),
+                _ => match matches.value_of("smart") {
+                    Some(default) => {
+                        Some(state.0.get(&key).copied().unwrap_or(parse_gaps(default)?))
+                    }
+                    None => state.0.get(&key).copied(),
+                },
+            };
+            if let Some(chosen) = chosen.filter(|&g| Some(g) != measured) {
+                changes.push((workspace, chosen));
+            }
+        }
+    }
+    if matches.is_present("toggle") {
+        server::save_state(STATE, &state)?;
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let mut commands = vec![];
+    for (workspace, gaps) in changes.iter() {
+        if workspace.id != focused.id {
+            commands.push(format!(
+                "workspace --no-auto-back-and-forth {}",
+                criteria::i3_quote(workspace.name.as_deref().unwrap_or(""))
+            ));
+        }
+        commands.push(format!("gaps inner current set {}", gaps.inner));
+        commands.push(format!("gaps outer current set {}", gaps.outer));
+    }
+    // Show the workspaces that were visible before, ending with the one that was focused.
+    if changes
+        .iter()
+        .any(|(workspace, _)| workspace.id != focused.id)
+    {
+        let visible = data.workspaces(conn).map_err(|e| format!("{}", e))?;
+        for workspace in visible.iter().filter(|w| w.visible && !w.focused) {
+            commands.push(format!(
+                "workspace --no-auto-back-and-forth {}",
+                criteria::i3_quote(&workspace.name)
+            ));
+        }
+        commands.push(format!(
+            "workspace --no-auto-back-and-forth {}",
+            criteria::i3_quote(focused.name.as_deref().unwrap_or(""))
+        ));
+    }
+    ipc::run_command(conn, &commands.join("; "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{node, tree};
+
+    /// A workspace (2) with a window (3) next to a tabbed container (4), which shows window 6 in
+    /// front of window 5.
+    fn tabbed() -> Node {
+        let mut tabs = node(
+            4,
+            "con",
+            None,
+            vec![
+                node(5, "con", Some(50), vec![]),
+                node(6, "con", Some(60), vec![]),
+            ],
+        );
+        tabs["layout"] = "tabbed".into();
+        tabs["focus"] = serde_json::json!([6, 5]);
+        let workspace = node(
+            2,
+            "workspace",
+            None,
+            vec![node(3, "con", Some(30), vec![]), tabs],
+        );
+        serde_json::from_value(workspace).unwrap()
+    }
+
+    #[test]
+    fn test_gaps() {
+        let gaps = |inner, outer| Gaps { inner, outer };
+        assert_eq!(parse_gaps("10"), Ok(gaps(10, 10)));
+        assert_eq!(parse_gaps("10/0"), Ok(gaps(10, 0)));
+        assert!(parse_gaps("-1").is_err());
+        assert!(parse_gaps("10/").is_err());
+
+        let presets = [gaps(0, 0), gaps(10, 10), gaps(20, 20)];
+        assert_eq!(next_gaps(&presets, Some(gaps(10, 10))), gaps(20, 20));
+        assert_eq!(next_gaps(&presets, Some(gaps(20, 20))), gaps(0, 0));
+        assert_eq!(next_gaps(&presets, Some(gaps(5, 5))), gaps(0, 0));
+        assert_eq!(next_gaps(&presets, None), gaps(0, 0));
+
+        // Windows 4 and 5 are inset from workspace 2, which is inset from the root.
+        let mut tree = tree();
+        tree.nodes[0].rect.x = 5;
+        tree.nodes[0].nodes[0].nodes[0].rect.x = 15;
+        tree.nodes[0].nodes[0].nodes[1].rect.x = 970;
+        assert_eq!(measured_gaps(&tree, &tree.nodes[0]), Some(gaps(10, 5)));
+        tree.nodes[0].nodes[0].nodes.clear();
+        assert_eq!(measured_gaps(&tree, &tree.nodes[0]), None);
+    }
+
+    #[test]
+    fn test_tiled_windows() {
+        let mut workspace = tabbed();
+        let ids = |workspace: &Node| {
+            tiled_windows(workspace)
+                .iter()
+                .map(|n| n.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&workspace), vec![3, 6]);
+        workspace.nodes[1].focus = vec![5, 6];
+        assert_eq!(ids(&workspace), vec![3, 5]);
+        // With a single window left, --smart removes the gaps even though the tabs hold two.
+        workspace.nodes.remove(0);
+        assert_eq!(ids(&workspace), vec![5]);
+    }
+}
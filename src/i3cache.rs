@@ -1,18 +1,31 @@
 //! A cache for i3 IPC output and tree search operations that may be expensive to repeat.
+//!
+//! Long-running commands can keep one cache around and update it from window events instead of
+//! fetching the whole tree again after every event. Events that can't be applied to the cached
+//! tree (e.g. `window::move`, which doesn't say where the window went) clear the cache instead, and
+//! `next_event` refetches the tree periodically in case the incremental model has drifted.
 
-use i3_ipc::reply::{Node, Outputs, Workspaces};
+use i3_ipc::event::{Event, Subscribe, WindowChange, WindowData};
+use i3_ipc::reply::{Node, NodeType, Outputs, Workspaces};
 use i3_ipc::I3Stream;
 
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
 use std::marker::PhantomPinned;
 use std::pin::Pin;
 
 use std::cell::Cell;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
 use std::io;
 
+use crate::diff::{self, Change};
+use crate::ipc::Ipc;
 use crate::search;
+use crate::urgency::Urgency;
 
 /// Holds the root `Node` of the i3 tree, as well as any references to other `Node`s inside this
 /// tree.
@@ -23,12 +36,29 @@ struct I3Nodes {
     _pin: PhantomPinned,
 }
 
+/// The containers around a node. Any of them may be null, e.g. the root has no parent, and
+/// outputs aren't on a workspace.
+#[derive(Clone, Copy)]
+struct Ancestry {
+    parent: *const Node,
+    workspace: *const Node,
+    output: *const Node,
+}
+
 /// Caches output from the i3 IPC channel, as well as results of search operations that may be
 /// expensive to repeat.
 pub struct I3Cache {
     nodes: Cell<Option<Pin<Box<I3Nodes>>>>,
     workspaces: RefCell<Option<Rc<Workspaces>>>,
     outputs: RefCell<Option<Rc<Outputs>>>,
+    /// Maps node ids to their ancestry, once it is first needed. The pointers point into the
+    /// pinned tree, so the map is cleared whenever the tree is modified.
+    ancestry: RefCell<HashMap<usize, Ancestry>>,
+    /// Maps window ids to the pids of their processes, which are expensive to look up.
+    pids: RefCell<HashMap<u32, Option<u32>>>,
+    urgency: RefCell<Option<Rc<Urgency>>>,
+    /// When the tree was last fetched from i3.
+    fetched: Cell<Option<Instant>>,
 }
 
 impl I3Cache {
@@ -37,6 +67,10 @@ impl I3Cache {
             nodes: Cell::new(None),
             workspaces: RefCell::new(None),
             outputs: RefCell::new(None),
+            ancestry: RefCell::new(HashMap::new()),
+            pids: RefCell::new(HashMap::new()),
+            urgency: RefCell::new(None),
+            fetched: Cell::new(None),
         }
     }
 
@@ -74,7 +108,7 @@ impl I3Cache {
         mut_ref
     }
 
-    pub fn full_tree(&self, conn: &mut I3Stream) -> io::Result<&Node> {
+    pub fn full_tree(&self, conn: &mut dyn Ipc) -> io::Result<&Node> {
         if self.nodes().is_none() {
             self.nodes.set(Some(Box::pin(I3Nodes {
                 full_tree: conn.get_tree()?,
@@ -82,11 +116,12 @@ impl I3Cache {
                 focused_workspace: std::ptr::null(),
                 _pin: PhantomPinned,
             })));
+            self.fetched.set(Some(Instant::now()));
         }
         Ok(&self.nodes().unwrap().full_tree)
     }
 
-    pub fn focused_node(&self, conn: &mut I3Stream) -> Result<&Node, String> {
+    pub fn focused_node(&self, conn: &mut dyn Ipc) -> Result<&Node, String> {
         self.full_tree(conn).map_err(|e| format!("{}", e))?;
         if self.nodes().unwrap().focused_node.is_null() {
             let nodes_mut = self.nodes_mut().unwrap();
@@ -101,8 +136,7 @@ impl I3Cache {
         Ok(focused_node)
     }
 
-    #[allow(dead_code)]
-    pub fn focused_workspace(&self, conn: &mut I3Stream) -> Result<&Node, String> {
+    pub fn focused_workspace(&self, conn: &mut dyn Ipc) -> Result<&Node, String> {
         let tree = self.full_tree(conn).map_err(|e| format!("{}", e))?;
         let workspaces = self.workspaces(conn).map_err(|e| format!("{}", e))?;
         if self.nodes().unwrap().focused_workspace.is_null() {
@@ -119,7 +153,7 @@ impl I3Cache {
         Ok(focused_workspace)
     }
 
-    pub fn workspaces(&self, conn: &mut I3Stream) -> io::Result<Rc<Workspaces>> {
+    pub fn workspaces(&self, conn: &mut dyn Ipc) -> io::Result<Rc<Workspaces>> {
         if self.workspaces.borrow().is_none() {
             self.workspaces
                 .borrow_mut()
@@ -128,8 +162,7 @@ impl I3Cache {
         Ok(Rc::clone(self.workspaces.borrow().as_ref().unwrap()))
     }
 
-    #[allow(dead_code)]
-    pub fn outputs(&self, conn: &mut I3Stream) -> io::Result<Rc<Outputs>> {
+    pub fn outputs(&self, conn: &mut dyn Ipc) -> io::Result<Rc<Outputs>> {
         if self.outputs.borrow().is_none() {
             self.outputs
                 .borrow_mut()
@@ -137,4 +170,489 @@ impl I3Cache {
         }
         Ok(Rc::clone(self.outputs.borrow().as_ref().unwrap()))
     }
+
+    fn ancestry(&self, conn: &mut dyn Ipc, id: usize) -> Option<Ancestry> {
+        let tree = self.full_tree(conn).ok()?;
+        let mut ancestry = self.ancestry.borrow_mut();
+        if ancestry.is_empty() {
+            let root = Ancestry {
+                parent: std::ptr::null(),
+                workspace: std::ptr::null(),
+                output: std::ptr::null(),
+            };
+            index_ancestry(tree, root, &mut ancestry);
+        }
+        ancestry.get(&id).copied()
+    }
+
+    /// Returns the parent of the node with the given id, unless it is the root.
+    pub fn parent(&self, conn: &mut dyn Ipc, id: usize) -> Option<&Node> {
+        // Safety: the pointers point into the pinned tree, and are cleared before it changes.
+        unsafe { self.ancestry(conn, id)?.parent.as_ref() }
+    }
+
+    /// Returns the workspace that contains the node with the given id, which may be the node
+    /// itself.
+    pub fn containing_workspace(&self, conn: &mut dyn Ipc, id: usize) -> Option<&Node> {
+        unsafe { self.ancestry(conn, id)?.workspace.as_ref() }
+    }
+
+    /// Returns the output that contains the node with the given id, which may be the node itself.
+    pub fn containing_output(&self, conn: &mut dyn Ipc, id: usize) -> Option<&Node> {
+        unsafe { self.ancestry(conn, id)?.output.as_ref() }
+    }
+
+    /// Returns the pid of the process that owns an X11 window. Each window is only looked up once.
+    pub fn window_pid(&self, conn: &mut dyn Ipc, window: u32) -> Option<u32> {
+        if let Some(&pid) = self.pids.borrow().get(&window) {
+            return pid;
+        }
+        let pid = conn.window_pid(window);
+        self.pids.borrow_mut().insert(window, pid);
+        pid
+    }
+
+    /// Returns the times windows became urgent. They are read again after the tree changes, since
+    /// window events change them as well.
+    pub fn urgency(&self, conn: &mut dyn Ipc) -> Rc<Urgency> {
+        if self.urgency.borrow().is_none() {
+            self.urgency.borrow_mut().replace(Rc::new(conn.urgency()));
+        }
+        Rc::clone(self.urgency.borrow().as_ref().unwrap())
+    }
+
+    /// Fetches everything that most commands need ahead of time. Errors are ignored, since they
+    /// will come up again when the data is actually used.
+    pub fn prefetch(&self, conn: &mut dyn Ipc) {
+        let _ = self.full_tree(conn);
+        let _ = self.workspaces(conn);
+        let _ = self.outputs(conn);
+    }
+
+    /// Returns the cached tree for modification. The cached references into the tree are cleared,
+    /// since they may no longer point at the right nodes afterwards.
+    fn tree_mut(&mut self) -> Option<&mut Node> {
+        self.ancestry.get_mut().clear();
+        self.urgency.get_mut().take();
+        let nodes = self.nodes.get_mut().as_mut()?;
+        // Safety: the nodes are only modified in place, never moved, and `&mut self` guarantees
+        // that there are no other references into the tree while it is being modified.
+        let nodes = unsafe { Pin::get_unchecked_mut(nodes.as_mut()) };
+        nodes.focused_node = std::ptr::null();
+        nodes.focused_workspace = std::ptr::null();
+        Some(&mut nodes.full_tree)
+    }
+
+    /// Drops everything, so that it is fetched again when it is next needed.
+    pub fn clear(&mut self) {
+        *self = I3Cache::new();
+    }
+
+    /// Returns how long ago the tree was fetched from i3, if it has been fetched.
+    pub fn tree_age(&self) -> Option<Duration> {
+        self.fetched.get().map(|fetched| fetched.elapsed())
+    }
+
+    /// Updates the cache after an i3 event. The whole cache is cleared if the event can't be
+    /// applied incrementally, or if the tree was fetched more than `resync` ago.
+    pub fn update(&mut self, event: &Event, resync: Duration) {
+        if self.tree_age().map_or(false, |age| age > resync) {
+            self.clear();
+            return;
+        }
+        match event {
+            Event::Window(ev) => {
+                if !self.apply_window_event(ev) {
+                    self.clear();
+                }
+            }
+            // Workspace and output changes affect focus, visibility and geometry all over the
+            // tree.
+            Event::Workspace(_) | Event::Output(_) => self.clear(),
+            _ => {}
+        }
+    }
+
+    /// Applies a window event to the cached tree. Returns false if the change can't be applied,
+    /// in which case the cache should be cleared.
+    fn apply_window_event(&mut self, event: &WindowData) -> bool {
+        let container = &event.container;
+        // X11 reuses window ids.
+        if let (WindowChange::Close, Some(window)) = (&event.change, container.window) {
+            self.pids.get_mut().remove(&window);
+        }
+        let tree = match self.tree_mut() {
+            Some(tree) => tree,
+            // Nothing is cached, so there is nothing to update.
+            None => return true,
+        };
+
+        let applied = match event.change {
+            WindowChange::New => insert_new(tree, container),
+            WindowChange::Close => remove_node(tree, container.id).is_some(),
+            WindowChange::Focus => focus_node(tree, container.id),
+            WindowChange::Title => match find_node_mut(tree, container.id) {
+                Some(node) => {
+                    node.name = container.name.clone();
+                    node.window_properties = container.window_properties.clone();
+                    true
+                }
+                None => false,
+            },
+            WindowChange::Mark => match find_node_mut(tree, container.id) {
+                Some(node) => {
+                    node.marks = container.marks.clone();
+                    true
+                }
+                None => false,
+            },
+            WindowChange::Urgent => match find_node_mut(tree, container.id) {
+                Some(node) => {
+                    node.urgent = container.urgent;
+                    update_urgency(tree);
+                    true
+                }
+                None => false,
+            },
+            WindowChange::FullscreenMode => match find_node_mut(tree, container.id) {
+                Some(node) => {
+                    node.fullscreen_mode = container.fullscreen_mode.clone();
+                    true
+                }
+                None => false,
+            },
+            // These events don't say where the container ended up.
+            WindowChange::Move | WindowChange::Floating => false,
+        };
+
+        // Focus, visibility and urgency of workspaces may have changed as well.
+        match event.change {
+            WindowChange::Title | WindowChange::Mark | WindowChange::FullscreenMode => {}
+            _ => {
+                self.workspaces.replace(None);
+            }
+        }
+        applied
+    }
+
+    /// Waits for the next event from `listen`. If the tree is due to be fetched again before an
+    /// event arrives, it is fetched in the meantime, so that idle daemons resync as well. Returns
+    /// `None` once i3 closes the event stream.
+    pub fn next_event(
+        &mut self,
+        conn: &mut dyn Ipc,
+        events: &Receiver<io::Result<Event>>,
+        resync: Duration,
+    ) -> Option<io::Result<Event>> {
+        loop {
+            let wait = self
+                .tree_age()
+                .map_or(resync, |age| resync.checked_sub(age).unwrap_or_default());
+            match events.recv_timeout(wait) {
+                Ok(event) => return Some(event),
+                Err(RecvTimeoutError::Timeout) => {
+                    self.clear();
+                    let _ = self.full_tree(conn);
+                }
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    /// Compares the cached tree with a freshly fetched one, and describes every difference.
+    /// Geometry, layout and borders aren't tracked by window events, so differences in `rect`,
+    /// `layout` and `border` are left out.
+    pub fn check(&self, conn: &mut dyn Ipc) -> Result<Vec<String>, String> {
+        let cached = self.full_tree(conn).map_err(|e| format!("{}", e))?;
+        let fresh = conn.get_tree().map_err(|e| format!("{}", e))?;
+        Ok(diff::diff_trees(cached, &fresh)
+            .iter()
+            .filter(|change| {
+                !matches!(
+                    change,
+                    Change::Property {
+                        property: "rect",
+                        ..
+                    } | Change::Property {
+                        property: "layout",
+                        ..
+                    } | Change::Property {
+                        property: "border",
+                        ..
+                    }
+                )
+            })
+            .map(|change| change.to_string())
+            .collect())
+    }
+}
+
+/// Subscribes to i3 events, and passes them on from a separate thread so that daemons can wait
+/// for them with a timeout.
+pub fn listen(subscriptions: &[Subscribe]) -> Result<Receiver<io::Result<Event>>, String> {
+    let mut events = I3Stream::conn_sub(subscriptions).map_err(|e| format!("{}", e))?;
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for event in events.listen() {
+            if sender.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+/// Reads the `--resync` option of a daemon.
+pub fn resync_interval(matches: &clap::ArgMatches) -> Result<Duration, String> {
+    let seconds = matches.value_of("resync").unwrap();
+    seconds
+        .parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|e| format!("--resync '{}': {}", seconds, e))
+}
+
+/// Prints the differences between the cached tree and i3's tree to stderr, for `--check`.
+pub fn report_drift(conn: &mut dyn Ipc, data: &I3Cache) {
+    match data.check(conn) {
+        Ok(differences) => {
+            for difference in differences.iter() {
+                eprintln!("cache: {}", difference);
+            }
+        }
+        Err(e) => eprintln!("cache: {}", e),
+    }
+}
+
+/// Adds `node` and its descendants to `index`, given the ancestry of `node`.
+fn index_ancestry(node: &Node, mut ancestry: Ancestry, index: &mut HashMap<usize, Ancestry>) {
+    match node.node_type {
+        NodeType::Output => ancestry.output = node,
+        NodeType::Workspace => ancestry.workspace = node,
+        _ => {}
+    }
+    index.insert(node.id, ancestry);
+    ancestry.parent = node;
+    for child in node.nodes.iter().chain(node.floating_nodes.iter()) {
+        index_ancestry(child, ancestry, index);
+    }
+}
+
+fn find_node_mut(node: &mut Node, id: usize) -> Option<&mut Node> {
+    if node.id == id {
+        return Some(node);
+    }
+    node.nodes
+        .iter_mut()
+        .chain(node.floating_nodes.iter_mut())
+        .find_map(|child| find_node_mut(child, id))
+}
+
+/// Returns the ids of the nodes on the path from `node` (exclusive) to the node with id `id`
+/// (inclusive).
+pub fn path_to(node: &Node, id: usize) -> Option<Vec<usize>> {
+    if node.id == id {
+        return Some(vec![]);
+    }
+    node.nodes
+        .iter()
+        .chain(node.floating_nodes.iter())
+        .find_map(|child| {
+            path_to(child, id).map(|mut path| {
+                path.insert(0, child.id);
+                path
+            })
+        })
+}
+
+/// Returns the nodes from the root down to the node with id `id`.
+pub fn lineage(tree: &Node, id: usize) -> Option<Vec<&Node>> {
+    let mut nodes = vec![tree];
+    for child_id in path_to(tree, id)? {
+        let parent = nodes[nodes.len() - 1];
+        let child = parent
+            .nodes
+            .iter()
+            .chain(parent.floating_nodes.iter())
+            .find(|n| n.id == child_id)?;
+        nodes.push(child);
+    }
+    Some(nodes)
+}
+
+/// i3 closes containers that have no children left, except for workspaces.
+fn is_empty_con(node: &Node) -> bool {
+    node.window.is_none()
+        && node.nodes.is_empty()
+        && node.floating_nodes.is_empty()
+        && (node.node_type == NodeType::Con || node.node_type == NodeType::FloatingCon)
+}
+
+/// Removes a node from the tree, along with any containers that are left empty.
+fn remove_node(parent: &mut Node, id: usize) -> Option<Node> {
+    if let Some(i) = parent.nodes.iter().position(|n| n.id == id) {
+        parent.focus.retain(|&f| f != id);
+        return Some(parent.nodes.remove(i));
+    }
+    if let Some(i) = parent.floating_nodes.iter().position(|n| n.id == id) {
+        parent.focus.retain(|&f| f != id);
+        return Some(parent.floating_nodes.remove(i));
+    }
+
+    let mut removed = None;
+    let mut emptied = None;
+    for child in parent
+        .nodes
+        .iter_mut()
+        .chain(parent.floating_nodes.iter_mut())
+    {
+        removed = remove_node(child, id);
+        if removed.is_some() {
+            if is_empty_con(child) {
+                emptied = Some(child.id);
+            }
+            break;
+        }
+    }
+    if let Some(emptied) = emptied {
+        remove_node(parent, emptied);
+    }
+    removed
+}
+
+/// Inserts a new tiling window next to the focused container, where i3 puts it. Returns false for
+/// floating windows, because i3 wraps them in a floating container that isn't in the event.
+fn insert_new(tree: &mut Node, container: &Node) -> bool {
+    let floating = serde_json::to_value(&container.floating)
+        .ok()
+        .and_then(|v| v.as_str().map(|f| f.ends_with("_on")))
+        .unwrap_or(false);
+    let focused = match search::i3_find_focused_node(tree) {
+        Some(focused) if !floating => focused.id,
+        _ => return false,
+    };
+    let path = match path_to(tree, focused) {
+        Some(path) => path,
+        None => return false,
+    };
+
+    let mut new = container.clone();
+    new.focused = false;
+    let focused_node = find_node_mut(tree, focused).unwrap();
+    if focused_node.node_type == NodeType::Workspace {
+        focused_node.focus.push(new.id);
+        focused_node.nodes.push(new);
+        return true;
+    }
+
+    let parent_id = match path.len() {
+        0 | 1 => return false,
+        n => path[n - 2],
+    };
+    let parent = find_node_mut(tree, parent_id).unwrap();
+    match parent.nodes.iter().position(|n| n.id == focused) {
+        Some(i) => {
+            parent.focus.push(new.id);
+            parent.nodes.insert(i + 1, new);
+            true
+        }
+        // The focused container is floating.
+        None => false,
+    }
+}
+
+/// Focuses a node: it becomes the only focused node, and moves to the front of the focus list of
+/// each of its ancestors.
+fn focus_node(tree: &mut Node, id: usize) -> bool {
+    let path = match path_to(tree, id) {
+        Some(path) => path,
+        None => return false,
+    };
+    clear_focused(tree);
+
+    let mut node = tree;
+    for &child in path.iter() {
+        node.focus.retain(|&f| f != child);
+        node.focus.insert(0, child);
+        let current = node;
+        node = current
+            .nodes
+            .iter_mut()
+            .chain(current.floating_nodes.iter_mut())
+            .find(|n| n.id == child)
+            .unwrap();
+    }
+    node.focused = true;
+    true
+}
+
+fn clear_focused(node: &mut Node) {
+    node.focused = false;
+    for child in node.nodes.iter_mut().chain(node.floating_nodes.iter_mut()) {
+        clear_focused(child);
+    }
+}
+
+/// Marks each workspace as urgent if any of its windows is urgent. Returns whether `node` contains
+/// an urgent window.
+fn update_urgency(node: &mut Node) -> bool {
+    let mut urgent = node.window.is_some() && node.urgent;
+    for child in node.nodes.iter_mut().chain(node.floating_nodes.iter_mut()) {
+        urgent |= update_urgency(child);
+    }
+    if node.node_type == NodeType::Workspace {
+        node.urgent = urgent;
+    }
+    urgent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{node, tree, TestIpc};
+
+    #[test]
+    fn test_remove_node() {
+        let mut tree = tree();
+        assert_eq!(remove_node(&mut tree, 5).map(|n| n.id), Some(5));
+        assert_eq!(path_to(&tree, 4), Some(vec![2, 3, 4]));
+        // Closing the last window also closes the split container around it.
+        assert!(remove_node(&mut tree, 4).is_some());
+        assert_eq!(path_to(&tree, 3), None);
+        assert!(tree.nodes[0].nodes.is_empty());
+        assert!(tree.nodes[0].focus.is_empty());
+        assert!(remove_node(&mut tree, 4).is_none());
+    }
+
+    #[test]
+    fn test_focus_node() {
+        let mut tree = tree();
+        assert!(focus_node(&mut tree, 5));
+        assert_eq!(tree.nodes[0].nodes[0].focus, vec![5, 4]);
+        assert!(!tree.nodes[0].nodes[0].nodes[0].focused);
+        assert!(tree.nodes[0].nodes[0].nodes[1].focused);
+        assert!(!focus_node(&mut tree, 6));
+
+        let mut new: Node = serde_json::from_value(node(6, "con", Some(60), vec![])).unwrap();
+        new.focused = true;
+        assert!(insert_new(&mut tree, &new));
+        assert_eq!(path_to(&tree, 6), Some(vec![2, 3, 6]));
+        assert_eq!(tree.nodes[0].nodes[0].nodes[2].id, 6);
+        assert!(!tree.nodes[0].nodes[0].nodes[2].focused);
+    }
+
+    #[test]
+    fn test_ancestry() {
+        let (conn, mut data) = (&mut TestIpc(tree()), I3Cache::new());
+        let id = |node: Option<&Node>| node.map(|n| n.id);
+        assert_eq!(id(data.parent(conn, 4)), Some(3));
+        assert_eq!(id(data.parent(conn, 1)), None);
+        assert_eq!(id(data.containing_workspace(conn, 4)), Some(2));
+        assert_eq!(id(data.containing_workspace(conn, 2)), Some(2));
+        assert_eq!(id(data.containing_output(conn, 4)), None);
+
+        // The index is rebuilt after the tree changes.
+        remove_node(data.tree_mut().unwrap(), 5);
+        assert_eq!(id(data.parent(conn, 5)), None);
+        assert_eq!(id(data.parent(conn, 4)), Some(3));
+    }
 }
@@ -0,0 +1,92 @@
+//! Helpers for talking to i3 over the IPC channel.
+
+use i3_ipc::reply::{Node, Outputs, Success, Workspaces};
+use i3_ipc::I3Stream;
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::criteria;
+use crate::process;
+use crate::urgency::{self, Urgency};
+
+/// The requests oi3h makes over the IPC channel. Besides a live connection to i3, this is
+/// implemented by `oi3h replay`, which answers them from a recording.
+pub trait Ipc {
+    fn get_tree(&mut self) -> io::Result<Node>;
+    fn get_workspaces(&mut self) -> io::Result<Workspaces>;
+    fn get_outputs(&mut self) -> io::Result<Outputs>;
+    fn run_command(&mut self, command: &str) -> io::Result<Vec<Success>>;
+
+    /// Returns the times windows became urgent. This isn't an i3 request, but replays need to
+    /// answer it from the recording rather than from the live state file.
+    fn urgency(&mut self) -> Urgency {
+        urgency::load()
+    }
+
+    /// Returns the pid of the process that owns an X11 window. Like `urgency`, this is answered
+    /// from the recording when replaying.
+    fn window_pid(&mut self, window: u32) -> Option<u32> {
+        process::window_pid(window)
+    }
+
+    /// Returns true if commands aren't really being run, in which case side effects outside of
+    /// i3 (like running nested oi3h commands) should be skipped as well.
+    fn replaying(&self) -> bool {
+        false
+    }
+}
+
+impl Ipc for I3Stream {
+    fn get_tree(&mut self) -> io::Result<Node> {
+        I3Stream::get_tree(self)
+    }
+
+    fn get_workspaces(&mut self) -> io::Result<Workspaces> {
+        I3Stream::get_workspaces(self)
+    }
+
+    fn get_outputs(&mut self) -> io::Result<Outputs> {
+        I3Stream::get_outputs(self)
+    }
+
+    fn run_command(&mut self, command: &str) -> io::Result<Vec<Success>> {
+        I3Stream::run_command(self, command)
+    }
+}
+
+/// Number of commands sent to i3 by this process, so that `oi3h server` knows when the commands it
+/// ran on behalf of a client have made its cache stale.
+static COMMANDS_RUN: AtomicUsize = AtomicUsize::new(0);
+
+pub fn commands_run() -> usize {
+    COMMANDS_RUN.load(Ordering::SeqCst)
+}
+
+/// Runs an i3 command, turning both IPC errors and unsuccessful command replies into an error
+/// message.
+pub fn run_command(conn: &mut dyn Ipc, command: &str) -> Result<(), String> {
+    COMMANDS_RUN.fetch_add(1, Ordering::SeqCst);
+    let replies = conn
+        .run_command(command)
+        .map_err(|e| format!("{}: {}", command, e))?;
+    for reply in replies.iter() {
+        if !reply.success {
+            return Err(format!(
+                "{}: {}",
+                command,
+                reply.error.as_deref().unwrap_or("command failed")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Asks i3 to run a shell command. The command is quoted so that i3 doesn't treat any `;` or `,`
+/// inside it as the start of another i3 command.
+pub fn exec(conn: &mut dyn Ipc, command: &str) -> Result<(), String> {
+    run_command(
+        conn,
+        &format!("exec --no-startup-id {}", criteria::i3_quote(command)),
+    )
+}
@@ -0,0 +1,227 @@
+//! Saves workspace layouts in the format used by i3's `append_layout` command, and restores them.
+
+use regex::Regex;
+use serde_json::{json, Map, Value};
+
+use i3_ipc::reply::{Node, NodeType};
+
+use crate::criteria::{self, ClassMap};
+use crate::i3cache::I3Cache;
+use crate::ipc::{self, Ipc};
+use crate::server;
+
+/// Window properties that can be used to generate swallow criteria.
+pub const SWALLOW_FIELDS: &[&str] = &["class", "instance", "title", "window_role"];
+
+/// Builds the `append_layout` representation of `node` and its children. Windows are replaced by
+/// placeholders that swallow new windows matching the `swallow` properties of the original window.
+pub fn layout_node(node: &Node, swallow: &[&str]) -> Value {
+    let mut layout = Map::new();
+    layout.insert("type".to_string(), json!(node.node_type));
+    layout.insert("border".to_string(), json!(node.border));
+    layout.insert(
+        "current_border_width".to_string(),
+        json!(node.current_border_width),
+    );
+    layout.insert("layout".to_string(), json!(node.layout));
+    layout.insert("percent".to_string(), json!(node.percent));
+    layout.insert("floating".to_string(), json!(node.floating));
+    if let Some(name) = &node.name {
+        layout.insert("name".to_string(), json!(name));
+    }
+    if !node.marks.0.is_empty() {
+        layout.insert("marks".to_string(), json!(node.marks.0));
+    }
+    if node.node_type == NodeType::FloatingCon {
+        layout.insert("rect".to_string(), json!(node.rect));
+    }
+
+    if let Some(properties) = &node.window_properties {
+        layout.insert("geometry".to_string(), json!(node.geometry));
+        let mut swallows = Map::new();
+        for &field in swallow.iter() {
+            let value = match field {
+                "class" => properties.class.as_ref(),
+                "instance" => properties.instance.as_ref(),
+                "title" => properties.title.as_ref(),
+                "window_role" => properties.window_role.as_ref(),
+                _ => None,
+            };
+            if let Some(value) = value {
+                swallows.insert(
+                    field.to_string(),
+                    json!(format!("^{}$", regex::escape(value))),
+                );
+            }
+        }
+        layout.insert("swallows".to_string(), json!([swallows]));
+    }
+
+    let nodes: Vec<Value> = node.nodes.iter().map(|n| layout_node(n, swallow)).collect();
+    if !nodes.is_empty() {
+        layout.insert("nodes".to_string(), json!(nodes));
+    }
+
+    Value::Object(layout)
+}
+
+/// Builds the layout of a workspace: one top-level value per tiling child, followed by one per
+/// floating container, just like `i3-save-tree` does.
+pub fn layout_workspace(workspace: &Node, swallow: &[&str]) -> Vec<Value> {
+    workspace
+        .nodes
+        .iter()
+        .chain(workspace.floating_nodes.iter())
+        .map(|n| layout_node(n, swallow))
+        .collect()
+}
+
+/// Formats layout values the way `append_layout` expects them: a sequence of JSON objects.
+pub fn format_layout(layout: &[Value]) -> String {
+    layout
+        .iter()
+        .map(|v| serde_json::to_string_pretty(v).unwrap())
+        .collect::<Vec<String>>()
+        .join("\n\n")
+        + "\n"
+}
+
+pub fn parse_layout(input: &str) -> Result<Vec<Value>, String> {
+    serde_json::Deserializer::from_str(input)
+        .into_iter::<Value>()
+        .collect::<Result<Vec<Value>, _>>()
+        .map_err(|e| format!("{}", e))
+}
+
+/// Returns the class of every window placeholder in a layout, reversing the escaping done by
+/// `layout_node`. Placeholders that don't swallow by class are skipped.
+pub fn swallowed_classes(layout: &[Value]) -> Vec<String> {
+    let escaped = Regex::new(r"\\(.)").unwrap();
+    let mut classes = vec![];
+    let mut pending: Vec<&Value> = layout.iter().rev().collect();
+    while let Some(value) = pending.pop() {
+        if let Some(swallows) = value.get("swallows").and_then(|s| s.as_array()) {
+            for class in swallows.iter().filter_map(|s| s.get("class")?.as_str()) {
+                let class = class.trim_start_matches('^').trim_end_matches('$');
+                classes.push(escaped.replace_all(class, "$1").into_owned());
+            }
+        }
+        if let Some(nodes) = value.get("nodes").and_then(|n| n.as_array()) {
+            pending.extend(nodes.iter().rev());
+        }
+    }
+    classes
+}
+
+/// Finds a workspace by exact name, or by number if no workspace has that name.
+pub fn find_workspace<'a>(
+    conn: &mut dyn Ipc,
+    data: &'a I3Cache,
+    query: &str,
+) -> Result<&'a Node, String> {
+    let workspaces = criteria::all_workspaces(criteria::matched_outputs(conn, data, &[])?);
+    let num = query.parse::<i32>().ok();
+    workspaces
+        .0
+        .iter()
+        .find(|w| w.name.as_deref() == Some(query))
+        .or_else(|| workspaces.0.iter().find(|w| num.is_some() && w.num == num))
+        .cloned()
+        .ok_or(format!("No workspace named '{}'", query))
+}
+
+fn save(matches: &clap::ArgMatches, conn: &mut dyn Ipc, data: &I3Cache) -> Result<(), String> {
+    let workspace = find_workspace(conn, data, matches.value_of("workspace").unwrap())?;
+    let swallow: Vec<&str> = matches.values_of("swallow").unwrap().collect();
+    let path = matches.value_of("file").unwrap();
+
+    let layout = layout_workspace(workspace, &swallow);
+    std::fs::write(server::resolve_path(path), format_layout(&layout))
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Appends a layout file to a workspace, creating the workspace if it doesn't exist. If `commands`
+/// is given, a program is launched for each placeholder whose class it maps to a command.
+pub fn restore_layout(
+    conn: &mut dyn Ipc,
+    workspace: &str,
+    path: &str,
+    commands: Option<&ClassMap>,
+) -> Result<(), String> {
+    let resolved = server::resolve_path(path);
+    let input = std::fs::read_to_string(&resolved).map_err(|e| format!("{}: {}", path, e))?;
+    let layout = parse_layout(&input).map_err(|e| format!("{}: {}", path, e))?;
+    // i3 resolves relative paths against its own working directory, not ours.
+    let path = std::fs::canonicalize(&resolved).map_err(|e| format!("{}: {}", path, e))?;
+
+    ipc::run_command(
+        conn,
+        &format!(
+            "workspace {}; append_layout {}",
+            criteria::i3_quote(workspace),
+            criteria::i3_quote(&path.to_string_lossy())
+        ),
+    )?;
+
+    if let Some(commands) = commands {
+        for class in swallowed_classes(&layout).iter() {
+            if let Some(command) = commands.get(class) {
+                ipc::exec(conn, command)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn restore(matches: &clap::ArgMatches, conn: &mut dyn Ipc) -> Result<(), String> {
+    let commands = matches
+        .value_of("commands")
+        .map(criteria::read_class_map)
+        .transpose()?;
+    restore_layout(
+        conn,
+        matches.value_of("workspace").unwrap(),
+        matches.value_of("file").unwrap(),
+        commands.as_ref(),
+    )
+}
+
+pub fn layout_subcmd(
+    matches: &clap::ArgMatches,
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    match matches.subcommand() {
+        ("save", Some(save_matches)) => save(save_matches, conn, data),
+        ("restore", Some(restore_matches)) => restore(restore_matches, conn),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swallowed_classes() {
+        let layout = parse_layout(
+            r#"
+            {"type": "con", "nodes": [
+                {"type": "con", "swallows": [{"class": "^Firefox$", "title": "^x$"}]},
+                {"type": "con", "swallows": [{"instance": "^term$"}]},
+                {"type": "con", "swallows": [{"class": "^Code\\ \\-\\ OSS$"}]}
+            ]}
+            {"type": "floating_con", "nodes": [
+                {"type": "con", "swallows": [{"class": "^mpv$"}]}
+            ]}
+            "#,
+        )
+        .unwrap();
+        assert_eq!(layout.len(), 2);
+        assert_eq!(
+            swallowed_classes(&layout),
+            vec!["Firefox", "Code - OSS", "mpv"]
+        );
+        assert!(parse_layout("{\"type\": ").is_err());
+    }
+}
@@ -1,16 +1,49 @@
 use clap;
 
-use i3_ipc::{Connect, I3Stream, I3};
+use i3_ipc::{Connect, I3};
 
+/// Like `println!`, except that the output is sent back to the client when running inside
+/// `oi3h server`.
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        $crate::server::print(format!($($arg)*))
+    };
+}
+
+mod balance;
 mod border;
+mod config;
 mod criteria;
+mod diff;
+mod expr;
+mod float;
+mod gaps;
 mod i3cache;
+mod ipc;
+mod layout;
+mod mark;
+mod output;
+mod pip;
+mod process;
+mod profile;
+mod query;
+mod record;
+mod resize;
+mod rules;
 mod search;
+mod server;
+mod session;
+#[cfg(test)]
+mod testutil;
+mod urgency;
+mod workspace;
+mod workspace_names;
 
 use i3cache::I3Cache;
+use ipc::Ipc;
 
-fn main() {
-    let matches = clap::App::new(clap::crate_name!())
+fn app() -> clap::App<'static, 'static> {
+    clap::App::new(clap::crate_name!())
         .version(clap::crate_version!())
         .about(clap::crate_description!())
         .author(clap::crate_authors!())
@@ -19,7 +52,7 @@ fn main() {
             clap::Arg::with_name("criteria")
                 .long("criteria")
                 .short("c")
-                .help("i3 command criteria for subsequent commands\n(terminate list with single ']' argument)")
+                .help("i3 command criteria for subsequent commands, either as one '[...]' argument,\nor as separate arguments (terminate list with single ']' argument)")
                 .takes_value(true)
                 .default_value("[")
                 .hide_default_value(true)
@@ -27,6 +60,30 @@ fn main() {
                 .value_terminator("]")
                 .validator(criteria::validate_criteria),
         )
+        .arg(
+            clap::Arg::with_name("where")
+                .long("where")
+                .short("w")
+                .help("Boolean expression over criteria, combined with any other criteria\n(e.g. 'any(class=firefox, class=chromium) and not floating')")
+                .takes_value(true)
+                .validator(expr::validate_expr),
+        )
+        .arg(
+            clap::Arg::with_name("config")
+                .long("config")
+                .help("Config file with named values that can be referenced as '@name'\n(default: $XDG_CONFIG_HOME/oi3h/config.toml)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("unless-fullscreen")
+                .long("unless-fullscreen")
+                .help("Do nothing if a window is fullscreen on the focused workspace\n(only for commands that run once)"),
+        )
+        .arg(
+            clap::Arg::with_name("no-server")
+                .long("no-server")
+                .help("Talk to i3 directly even if 'oi3h server' is running"),
+        )
         .subcommand(
             clap::SubCommand::with_name("border")
                 .about("Modify window border")
@@ -34,39 +91,578 @@ fn main() {
                     clap::Arg::with_name("toggle")
                         .long("toggle")
                         .short("t")
-                        .help("Toggle between a list of border styles")
+                        .help("Toggle between a list of border styles (or '@name' of a toggle list)")
                         .takes_value(true)
                         .multiple(true)
                         .validator(border::validate_border),
                 ),
         )
+        .subcommand(
+            clap::SubCommand::with_name("workspace-names")
+                .about("Rename workspaces to '<num>: <icons>' based on their windows' classes")
+                .arg(
+                    clap::Arg::with_name("icons")
+                        .long("icons")
+                        .short("i")
+                        .help("File mapping window classes to icons, or '@name' of an icon map\n(one '<class regex> = <icon>' per line)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("default-icon")
+                        .long("default-icon")
+                        .short("d")
+                        .help("Icon for classes that aren't in the icon map (default: class name)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("unique")
+                        .long("unique")
+                        .short("u")
+                        .help("Only show one icon per class"),
+                )
+                .arg(
+                    clap::Arg::with_name("once")
+                        .long("once")
+                        .help("Rename workspaces once and exit instead of listening for events"),
+                )
+                .arg(
+                    clap::Arg::with_name("resync")
+                        .long("resync")
+                        .help("Seconds between full refreshes of the tree, which is otherwise updated from events")
+                        .takes_value(true)
+                        .value_name("seconds")
+                        .default_value("60"),
+                )
+                .arg(
+                    clap::Arg::with_name("check")
+                        .long("check")
+                        .help("After each event, report differences between the updated tree and i3's tree"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("workspace")
+                .about("Workspace operations on the output(s) selected by the criteria")
+                .arg(
+                    clap::Arg::with_name("next-free")
+                        .long("next-free")
+                        .short("n")
+                        .help("Switch to the next free workspace number"),
+                )
+                .arg(
+                    clap::Arg::with_name("move-to-next-free")
+                        .long("move-to-next-free")
+                        .short("m")
+                        .help("Move the focused container to the next free workspace number"),
+                )
+                .arg(
+                    clap::Arg::with_name("follow")
+                        .long("follow")
+                        .short("f")
+                        .requires("move-to-next-free")
+                        .help("Switch to the workspace the container was moved to"),
+                )
+                .arg(
+                    clap::Arg::with_name("compact")
+                        .long("compact")
+                        .help("Renumber workspaces on each output contiguously"),
+                )
+                .arg(
+                    clap::Arg::with_name("swap")
+                        .long("swap")
+                        .short("s")
+                        .help("Swap the numbers of two workspaces")
+                        .takes_value(true)
+                        .number_of_values(2)
+                        .value_names(&["A", "B"]),
+                )
+                .arg(
+                    clap::Arg::with_name("cycle")
+                        .long("cycle")
+                        .help("Switch to the next or previous workspace")
+                        .takes_value(true)
+                        .possible_values(&["next", "prev"]),
+                )
+                .arg(
+                    clap::Arg::with_name("skip-empty")
+                        .long("skip-empty")
+                        .short("e")
+                        .requires("cycle")
+                        .help("Skip workspaces without windows when cycling"),
+                )
+                .arg(
+                    clap::Arg::with_name("class")
+                        .long("class")
+                        .requires("cycle")
+                        .help("Only cycle between workspaces with a window of this class (regex)")
+                        .takes_value(true),
+                )
+                .group(clap::ArgGroup::with_name("operation").args(&[
+                    "cycle",
+                    "next-free",
+                    "move-to-next-free",
+                    "compact",
+                    "swap",
+                ])),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("output")
+                .about("List active outputs, or move workspaces between them")
+                .arg(
+                    clap::Arg::with_name("move-workspace")
+                        .long("move-workspace")
+                        .short("m")
+                        .help("Move the workspace(s) selected by the criteria to another output\n(left, right, up, down, next, prev, or an output name)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("follow")
+                        .long("follow")
+                        .short("f")
+                        .requires("move-workspace")
+                        .help("Keep focus on the moved workspace"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("outputs")
+                .about("Restore workspace-to-output assignments when the connected outputs change")
+                .arg(
+                    clap::Arg::with_name("profiles")
+                        .long("profiles")
+                        .short("p")
+                        .help("File containing output profiles")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("save")
+                        .long("save")
+                        .short("s")
+                        .help("Append a profile for the current assignments to the profiles file")
+                        .takes_value(true)
+                        .value_name("name"),
+                )
+                .arg(
+                    clap::Arg::with_name("once")
+                        .long("once")
+                        .help("Apply the matching profile once and exit instead of listening for events"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("layout")
+                .about("Save and restore workspace layouts")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("save")
+                        .about("Save a workspace layout in i3's append_layout format")
+                        .arg(clap::Arg::with_name("workspace").required(true))
+                        .arg(clap::Arg::with_name("file").required(true))
+                        .arg(
+                            clap::Arg::with_name("swallow")
+                                .long("swallow")
+                                .short("s")
+                                .help("Window properties used to match windows when restoring")
+                                .takes_value(true)
+                                .multiple(true)
+                                .use_delimiter(true)
+                                .possible_values(layout::SWALLOW_FIELDS)
+                                .default_value("class,instance,title"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("restore")
+                        .about("Append a saved layout to a workspace")
+                        .arg(clap::Arg::with_name("workspace").required(true))
+                        .arg(clap::Arg::with_name("file").required(true))
+                        .arg(
+                            clap::Arg::with_name("commands")
+                                .long("commands")
+                                .short("x")
+                                .help("File mapping window classes to the commands that launch them\n(one '<class regex> = <command>' per line)")
+                                .takes_value(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("session")
+                .about("Save and restore all outputs, workspaces and windows")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("save")
+                        .about("Save the current session")
+                        .arg(clap::Arg::with_name("file").required(true))
+                        .arg(
+                            clap::Arg::with_name("swallow")
+                                .long("swallow")
+                                .short("s")
+                                .help("Window properties used to match windows when restoring")
+                                .takes_value(true)
+                                .multiple(true)
+                                .use_delimiter(true)
+                                .possible_values(layout::SWALLOW_FIELDS)
+                                .default_value("class,instance"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("restore")
+                        .about("Rebuild a saved session")
+                        .arg(clap::Arg::with_name("file").required(true))
+                        .arg(
+                            clap::Arg::with_name("no-exec")
+                                .long("no-exec")
+                                .short("n")
+                                .help("Don't relaunch the programs that owned the saved windows"),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("daemon")
+                .about("Run commands in response to i3 events")
+                .arg(
+                    clap::Arg::with_name("rules")
+                        .long("rules")
+                        .short("r")
+                        .help("File containing rules, or '@name' of a rule set\n(one 'on <event> [where <criteria>] do <action>' per line)")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("resync")
+                        .long("resync")
+                        .help("Seconds between full refreshes of the tree, which is otherwise updated from events")
+                        .takes_value(true)
+                        .value_name("seconds")
+                        .default_value("60"),
+                )
+                .arg(
+                    clap::Arg::with_name("check")
+                        .long("check")
+                        .help("After each event, report differences between the updated tree and i3's tree"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("mark")
+                .about("Manage marks on the containers selected by the criteria, or the focused container")
+                .arg(
+                    clap::Arg::with_name("add")
+                        .long("add")
+                        .short("a")
+                        .help("Add a mark")
+                        .takes_value(true)
+                        .value_name("mark"),
+                )
+                .arg(
+                    clap::Arg::with_name("toggle")
+                        .long("toggle")
+                        .short("t")
+                        .help("Add a mark, or remove it if the container already has it")
+                        .takes_value(true)
+                        .value_name("mark"),
+                )
+                .arg(
+                    clap::Arg::with_name("remove")
+                        .long("remove")
+                        .short("r")
+                        .help("Remove a mark")
+                        .takes_value(true)
+                        .value_name("mark"),
+                )
+                .arg(
+                    clap::Arg::with_name("list")
+                        .long("list")
+                        .short("l")
+                        .help("List marks, with the id and title of the container that has each one"),
+                )
+                .arg(
+                    clap::Arg::with_name("next-free-digit")
+                        .long("next-free-digit")
+                        .short("n")
+                        .help("Mark the container with the first digit that isn't already a mark, and print it"),
+                )
+                .arg(
+                    clap::Arg::with_name("jump")
+                        .long("jump")
+                        .short("j")
+                        .help("Focus the next container with a mark matching this regex, in order of marks")
+                        .takes_value(true)
+                        .value_name("regex"),
+                )
+                .arg(
+                    clap::Arg::with_name("reverse")
+                        .long("reverse")
+                        .requires("jump")
+                        .help("Jump to the previous container instead"),
+                )
+                .group(
+                    clap::ArgGroup::with_name("operation")
+                        .args(&["add", "toggle", "remove", "list", "next-free-digit", "jump"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("float")
+                .about("Float the windows selected by the criteria, or the focused window, and place them on their workspaces")
+                .arg(
+                    clap::Arg::with_name("place")
+                        .long("place")
+                        .short("p")
+                        .help("center, top-left, top, top-right, left, right, bottom-left, bottom or bottom-right,\nor 'grid NxM:i' for cell i of an N columns by M rows grid ('grid NxM' or 'grid'\nto tile every selected window)")
+                        .takes_value(true)
+                        .value_name("placement")
+                        .required(true)
+                        .validator(float::validate_placement),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("resize")
+                .about("Resize the containers selected by the criteria, or the focused container")
+                .arg(
+                    clap::Arg::with_name("grow")
+                        .long("grow")
+                        .short("g")
+                        .help("Grow by this many pixels (or percentage points) towards left, right, up or down,\nor away from that direction if the container is already at that edge")
+                        .takes_value(true)
+                        .number_of_values(2)
+                        .value_names(&["direction", "amount"])
+                        .validator(resize::validate_grow),
+                )
+                .arg(
+                    clap::Arg::with_name("ratio")
+                        .long("ratio")
+                        .short("r")
+                        .help("Set the size to this fraction of the split container (e.g. 0.6)")
+                        .takes_value(true)
+                        .validator(resize::validate_ratio),
+                )
+                .group(
+                    clap::ArgGroup::with_name("operation")
+                        .args(&["grow", "ratio"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("balance")
+                .about("Give the children of the focused container's parent equal sizes,\nor those of the workspaces selected by the criteria")
+                .arg(
+                    clap::Arg::with_name("recursive")
+                        .long("recursive")
+                        .short("r")
+                        .help("Also balance every split container inside them"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("gaps")
+                .about("Change the gaps of the focused workspace, or the workspaces selected by the criteria")
+                .arg(
+                    clap::Arg::with_name("toggle")
+                        .long("toggle")
+                        .short("t")
+                        .help("Cycle through a list of gaps, each either '<px>' or '<inner px>/<outer px>'")
+                        .takes_value(true)
+                        .multiple(true)
+                        .value_name("gaps")
+                        .validator(gaps::validate_gaps),
+                )
+                .arg(
+                    clap::Arg::with_name("smart")
+                        .long("smart")
+                        .short("s")
+                        .help("Remove the gaps of workspaces with a single window, and restore the gaps chosen\nwith --toggle (or these gaps) on workspaces with more windows")
+                        .takes_value(true)
+                        .min_values(0)
+                        .max_values(1)
+                        .value_name("gaps")
+                        .validator(gaps::validate_gaps),
+                )
+                .group(
+                    clap::ArgGroup::with_name("operation")
+                        .args(&["toggle", "smart"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("pip")
+                .about("Shrink the window selected by the criteria, or the focused window, into a sticky\nfloating window in a corner of its output, or restore it if it already is")
+                .arg(
+                    clap::Arg::with_name("size")
+                        .long("size")
+                        .short("s")
+                        .help("Size of the window (default: [pip] size from the config file, or 480x270)")
+                        .takes_value(true)
+                        .value_name("size")
+                        .validator(pip::validate_size),
+                )
+                .arg(
+                    clap::Arg::with_name("corner")
+                        .long("corner")
+                        .help("Position of the window, as for 'float --place' (default: [pip] corner from the\nconfig file, or bottom-right)")
+                        .takes_value(true)
+                        .value_name("corner")
+                        .validator(pip::validate_corner),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("query")
+                .about("Print information about the state of i3, or about the containers selected by the criteria")
+                .arg(
+                    clap::Arg::with_name("query")
+                        .required(true)
+                        .help("'fullscreen' to print the fullscreen mode of the focused workspace (none, output or\nglobal), or a format string to print for each container selected by the criteria\n(or the focused container), e.g. '{workspace}:{class} {title}'. Fields: con_id, window,\nclass, title, workspace, output, layout, border, rect, marks")
+                        .validator(query::validate_query),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("record")
+                .about("Save i3 events to a file, for replaying them later")
+                .arg(clap::Arg::with_name("file").required(true)),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("replay")
+                .about("Feed recorded events to the rules, printing commands instead of running them")
+                .arg(clap::Arg::with_name("file").required(true))
+                .arg(
+                    clap::Arg::with_name("rules")
+                        .long("rules")
+                        .short("r")
+                        .help("File containing rules, or '@name' of a rule set")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("server")
+                .about("Keep a cache of the i3 tree warm for other oi3h commands"),
+        )
         .subcommand(clap::SubCommand::with_name("window").about("Find largest window"))
-        .subcommand(clap::SubCommand::with_name("tree").about("Test"))
-        .subcommand(clap::SubCommand::with_name("match").about("Test"))
-        .get_matches();
-
-    let criteria: Vec<criteria::Match> = matches.values_of("criteria").map_or(vec![], |cr_args| {
-        cr_args
-            .filter_map(|cr| criteria::parse_criteria(cr).transpose())
-            .collect::<Result<Vec<criteria::Match>, String>>()
-            .unwrap() // already validated by clap
+        .subcommand(
+            clap::SubCommand::with_name("tree")
+                .about("Print container ids, or compare two trees")
+                .arg(
+                    clap::Arg::with_name("diff")
+                        .long("diff")
+                        .short("d")
+                        .help("Compare two trees saved with 'i3-msg -t get_tree', or one with the current tree")
+                        .takes_value(true)
+                        .min_values(1)
+                        .max_values(2)
+                        .value_names(&["old", "new"]),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("match")
+                .about("Show how the criteria select outputs, workspaces and containers"),
+        )
+}
+
+fn main() {
+    let args = criteria::terminate_criteria_args(std::env::args().collect());
+    let matches = app().get_matches_from(&args);
+
+    // Commands that keep running would only check for a fullscreen window when they start.
+    if matches.is_present("unless-fullscreen") && !server::runs_once(&matches) {
+        eprintln!("--unless-fullscreen only applies to commands that run once");
+        std::process::exit(1);
+    }
+
+    if server::forwardable(&matches) {
+        if let Some(status) = server::forward(&args) {
+            std::process::exit(status);
+        }
+    }
+
+    let result = match matches.subcommand() {
+        // Replays don't need i3 at all.
+        ("replay", Some(replay_matches)) => config::load(matches.value_of("config"))
+            .and_then(|config| record::replay_subcmd(replay_matches, &config)),
+        ("server", Some(server_matches)) => {
+            server::server_subcmd(server_matches, &mut I3::connect().unwrap())
+        }
+        _ => run(&matches, &mut I3::connect().unwrap(), &I3Cache::new()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Runs a one-shot command, either directly or on behalf of a client of `oi3h server`.
+fn run(matches: &clap::ArgMatches, conn: &mut dyn Ipc, data: &I3Cache) -> Result<(), String> {
+    // The config file is only read by the commands that use it, so that a mistake in it doesn't
+    // break every other command.
+    let uses_config = matches!(
+        matches.subcommand_name(),
+        Some("border") | Some("workspace-names") | Some("daemon") | Some("pip")
+    ) || matches.values_of("criteria").map_or(false, |mut args| {
+        args.any(|arg| config::reference(arg).is_some())
     });
+    let config = match uses_config {
+        true => config::load(matches.value_of("config"))?,
+        false => config::Config::default(),
+    };
+    let criteria: Vec<criteria::Match> = matches
+        .values_of("criteria")
+        .map_or(Ok(vec![]), |cr_args| config.resolve_criteria(cr_args))?;
 
-    println!("Criteria: {:?}", criteria);
+    // These select workspaces and outputs with the criteria list, which can't express `or` or
+    // `not`, so an expression would be silently ignored.
+    let takes_expr = !matches!(
+        matches.subcommand_name(),
+        Some("workspace") | Some("output") | Some("gaps") | Some("balance")
+    );
+    if matches.is_present("where") && !takes_expr {
+        return Err(format!(
+            "--where can't be used with '{}'; use --criteria instead",
+            matches.subcommand_name().unwrap()
+        ));
+    }
 
-    let mut conn = I3::connect().unwrap();
-    let data = I3Cache::new();
+    let expr = match matches.value_of("where") {
+        Some(input) => expr::Expr::from_criteria(&criteria).and(expr::parse_expr(input)?),
+        None => expr::Expr::from_criteria(&criteria),
+    };
+
+    if matches.is_present("unless-fullscreen") && query::focused_fullscreen(conn, data)?.is_some() {
+        return Ok(());
+    }
 
     match matches.subcommand() {
-        ("border", Some(border_matches)) => border::border_subcmd(border_matches, &mut conn, &data),
-        ("window", Some(window_matches)) => window_subcmd(window_matches, &mut conn, &data),
-        ("tree", Some(tree_matches)) => tree_subcmd(tree_matches, &mut conn, &data),
-        ("match", Some(match_matches)) => match_subcmd(match_matches, &criteria, &mut conn, &data),
+        ("border", Some(border_matches)) => {
+            border::border_subcmd(border_matches, &config, conn, data)
+        }
+        ("workspace-names", Some(names_matches)) => {
+            workspace_names::workspace_names_subcmd(names_matches, &config, conn, data)
+        }
+        ("workspace", Some(workspace_matches)) => {
+            workspace::workspace_subcmd(workspace_matches, &criteria, conn, data)
+        }
+        ("output", Some(output_matches)) => {
+            output::output_subcmd(output_matches, &criteria, conn, data)
+        }
+        ("outputs", Some(outputs_matches)) => profile::outputs_subcmd(outputs_matches, conn, data),
+        ("layout", Some(layout_matches)) => layout::layout_subcmd(layout_matches, conn, data),
+        ("session", Some(session_matches)) => session::session_subcmd(session_matches, conn, data),
+        ("daemon", Some(daemon_matches)) => rules::daemon_subcmd(daemon_matches, &config, conn),
+        ("record", Some(record_matches)) => record::record_subcmd(record_matches, conn),
+        ("mark", Some(mark_matches)) => mark::mark_subcmd(mark_matches, &expr, conn, data),
+        ("float", Some(float_matches)) => float::float_subcmd(float_matches, &expr, conn, data),
+        ("query", Some(query_matches)) => query::query_subcmd(query_matches, &expr, conn, data),
+        ("pip", Some(pip_matches)) => pip::pip_subcmd(pip_matches, &expr, &config, conn, data),
+        ("gaps", Some(gaps_matches)) => gaps::gaps_subcmd(gaps_matches, &criteria, conn, data),
+        ("balance", Some(balance_matches)) => {
+            balance::balance_subcmd(balance_matches, &criteria, conn, data)
+        }
+        ("resize", Some(resize_matches)) => {
+            resize::resize_subcmd(resize_matches, &expr, conn, data)
+        }
+        ("window", Some(window_matches)) => {
+            window_subcmd(window_matches, conn, data);
+            Ok(())
+        }
+        ("tree", Some(tree_matches)) => tree_subcmd(tree_matches, conn, data),
+        ("match", Some(match_matches)) => match_subcmd(match_matches, &expr, conn, data),
         _ => unreachable!(),
     }
 }
 
-fn window_subcmd(_matches: &clap::ArgMatches, conn: &mut I3Stream, data: &I3Cache) {
+fn window_subcmd(_matches: &clap::ArgMatches, conn: &mut dyn Ipc, data: &I3Cache) {
     //let tree = data.full_tree(conn).unwrap();
     //let workspaces = data.workspaces(conn).unwrap();
     let focused = data.focused_node(conn).unwrap();
@@ -74,29 +670,40 @@ fn window_subcmd(_matches: &clap::ArgMatches, conn: &mut I3Stream, data: &I3Cach
     let workspace = data.focused_workspace(conn).unwrap();
     let largest = search::i3_find_largest_tiled_window(&workspace).unwrap();
 
-    println!("focused window: {:?}", focused.name);
-    println!("focused workspace: {:?}", workspace.name);
-    println!("largest window: {:?}", largest.name);
+    outln!("focused window: {:?}", focused.name);
+    outln!("focused workspace: {:?}", workspace.name);
+    outln!("largest window: {:?}", largest.name);
 }
 
-fn tree_subcmd(_matches: &clap::ArgMatches, conn: &mut I3Stream, data: &I3Cache) {
-    let tree = data.full_tree(conn).unwrap();
+fn tree_subcmd(
+    matches: &clap::ArgMatches,
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    if let Some(files) = matches.values_of("diff") {
+        return diff::tree_diff(&files.collect::<Vec<&str>>(), conn, data);
+    }
+
+    let tree = data.full_tree(conn).map_err(|e| format!("{}", e))?;
 
     use search::TreeIter;
 
     for elem in TreeIter::from(tree) {
-        println!("id: {}", elem.id);
+        outln!("id: {}", elem.id);
     }
+    Ok(())
 }
 
 fn match_subcmd(
     _matches: &clap::ArgMatches,
-    criteria: &[criteria::Match],
-    conn: &mut I3Stream,
+    expr: &expr::Expr,
+    conn: &mut dyn Ipc,
     data: &I3Cache,
-) {
-    let all_outputs = criteria::all_outputs(conn, data);
-    println!(
+) -> Result<(), String> {
+    outln!("Criteria: {}", expr);
+    let criteria = expr.required();
+    let all_outputs = criteria::all_outputs(conn, data)?;
+    outln!(
         "all outputs: {:?}",
         all_outputs
             .0
@@ -109,10 +716,9 @@ fn match_subcmd(
     for oc in criteria.iter() {
         match oc {
             criteria::Match::Output(p) => {
-                filtered_outputs = criteria::match_output(conn, data, filtered_outputs, p);
-                println!("pattern: {}", p);
-                //println!("filtered outputs: {:?}", filtered_outputs);
-                println!(
+                filtered_outputs = criteria::match_output(conn, data, filtered_outputs, p)?;
+                outln!("pattern: {}", p);
+                outln!(
                     "filtered outputs: {:?}",
                     filtered_outputs
                         .0
@@ -126,7 +732,7 @@ fn match_subcmd(
     }
 
     let all_workspaces = criteria::all_workspaces(filtered_outputs);
-    println!(
+    outln!(
         "all workspaces on selected output(s): {:?}",
         all_workspaces
             .0
@@ -139,9 +745,10 @@ fn match_subcmd(
     for oc in criteria.iter() {
         match oc {
             criteria::Match::Workspace(p) => {
-                filtered_workspaces = criteria::match_workspace(conn, data, filtered_workspaces, p);
-                println!("pattern: {}", p);
-                println!(
+                filtered_workspaces =
+                    criteria::match_workspace(conn, data, filtered_workspaces, p)?;
+                outln!("pattern: {}", p);
+                outln!(
                     "filtered workspaces: {:?}",
                     filtered_workspaces
                         .0
@@ -153,4 +760,20 @@ fn match_subcmd(
             _ => {}
         }
     }
+
+    let matched = criteria::i3_criteria_search(conn, data, expr)?;
+    outln!(
+        "matched containers: {:?}",
+        matched
+            .iter()
+            .map(|n| (n.id, n.name.as_ref()))
+            .collect::<Vec<_>>()
+    );
+    for node in matched.iter() {
+        outln!("{} {:?}", node.id, node.name.as_deref().unwrap_or(""));
+        for line in expr.explain(conn, data, node) {
+            outln!("  {}", line);
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,156 @@
+//! Mark management. Operations apply to the containers selected by the criteria, or to the focused
+//! container if there are no criteria.
+
+use regex::Regex;
+
+use i3_ipc::reply::Node;
+
+use crate::criteria;
+use crate::expr::Expr;
+use crate::i3cache::I3Cache;
+use crate::ipc::{self, Ipc};
+use crate::search;
+
+/// Digits in the order they appear on the keyboard.
+const DIGITS: [&str; 10] = ["1", "2", "3", "4", "5", "6", "7", "8", "9", "0"];
+
+/// Returns every marked container in the tree, along with each of its marks.
+fn all_marks(tree: &Node) -> Vec<(&str, &Node)> {
+    search::i3_tree_find_all(tree, |n| !n.marks.0.is_empty())
+        .into_iter()
+        .flat_map(|n| n.marks.0.iter().map(move |m| (m.as_str(), n)))
+        .collect()
+}
+
+/// Returns the first digit that isn't used as a mark.
+fn next_free_digit(used: &[&str]) -> Option<&'static str> {
+    DIGITS.iter().find(|d| !used.contains(d)).copied()
+}
+
+/// Returns the container to jump to: the one after the focused container among the containers
+/// with a matching mark, ordered by mark. Containers with several matching marks are visited once,
+/// at their first matching mark.
+fn jump_target<'a>(
+    marks: &[(&str, &'a Node)],
+    pattern: &Regex,
+    focused: usize,
+    reverse: bool,
+) -> Option<&'a Node> {
+    let mut candidates: Vec<(&str, &Node)> = marks
+        .iter()
+        .filter(|(mark, _)| pattern.is_match(mark))
+        .cloned()
+        .collect();
+    candidates.sort_by_key(|&(mark, _)| mark);
+    let mut nodes: Vec<&Node> = vec![];
+    for (_, node) in candidates {
+        if !nodes.iter().any(|n| n.id == node.id) {
+            nodes.push(node);
+        }
+    }
+    if reverse {
+        nodes.reverse();
+    }
+    let next = match nodes.iter().position(|n| n.id == focused) {
+        Some(i) => (i + 1) % nodes.len(),
+        None => 0,
+    };
+    nodes.get(next).copied()
+}
+
+pub fn mark_subcmd(
+    matches: &clap::ArgMatches,
+    expr: &Expr,
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    let tree = data.full_tree(conn).map_err(|e| format!("{}", e))?;
+    let marks = all_marks(tree);
+
+    if matches.is_present("list") {
+        let selected: Option<Vec<usize>> = match expr.criteria().is_empty() {
+            true => None,
+            false => Some(
+                criteria::i3_criteria_search(conn, data, expr)?
+                    .iter()
+                    .map(|n| n.id)
+                    .collect(),
+            ),
+        };
+        for (mark, node) in marks.iter() {
+            if selected.as_ref().map_or(true, |s| s.contains(&node.id)) {
+                outln!(
+                    "{}\t{}\t{}",
+                    mark,
+                    node.id,
+                    node.name.as_deref().unwrap_or("")
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(pattern) = matches.value_of("jump") {
+        let pattern = Regex::new(pattern).map_err(|e| format!("{}", e))?;
+        let focused = data.focused_node(conn)?.id;
+        let target = jump_target(&marks, &pattern, focused, matches.is_present("reverse"))
+            .ok_or(format!("No container has a mark matching '{}'", pattern))?;
+        return ipc::run_command(conn, &format!("[con_id={}] focus", target.id));
+    }
+
+    let nodes = criteria::selected_nodes(conn, data, expr)?;
+    let command = if let Some(mark) = matches.value_of("add") {
+        format!("mark --add {}", criteria::i3_quote(mark))
+    } else if let Some(mark) = matches.value_of("toggle") {
+        format!("mark --add --toggle {}", criteria::i3_quote(mark))
+    } else if let Some(mark) = matches.value_of("remove") {
+        format!("unmark {}", criteria::i3_quote(mark))
+    } else {
+        // --next-free-digit
+        if nodes.len() != 1 {
+            return Err("--next-free-digit can only mark a single container".to_string());
+        }
+        let used: Vec<&str> = marks.iter().map(|(mark, _)| *mark).collect();
+        let digit = next_free_digit(&used).ok_or("Every digit is already used as a mark")?;
+        outln!("{}", digit);
+        format!("mark --add {}", digit)
+    };
+
+    for node in nodes {
+        // `unmark <mark>` removes the mark from whichever container has it, so only run it on
+        // containers that actually have the mark.
+        if let Some(mark) = matches.value_of("remove") {
+            if !node.marks.0.iter().any(|m| m == mark) {
+                continue;
+            }
+        }
+        ipc::run_command(conn, &format!("[con_id={}] {}", node.id, command))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::tree;
+
+    #[test]
+    fn test_jump_target() {
+        let mut tree = tree();
+        tree.nodes[0].nodes[0].nodes[0].marks.0 = vec!["b".to_string()];
+        tree.nodes[0].nodes[0].nodes[1].marks.0 = vec!["a".to_string(), "x".to_string()];
+        let marks = all_marks(&tree);
+        assert_eq!(next_free_digit(&["1", "3"]), Some("2"));
+
+        let pattern = Regex::new("^[a-z]$").unwrap();
+        let jump = |focused, reverse| jump_target(&marks, &pattern, focused, reverse).map(|n| n.id);
+        assert_eq!(jump(1, false), Some(5));
+        assert_eq!(jump(5, false), Some(4));
+        assert_eq!(jump(4, false), Some(5));
+        assert_eq!(jump(5, true), Some(4));
+        assert_eq!(
+            jump_target(&marks, &Regex::new("^q").unwrap(), 4, false),
+            None
+        );
+    }
+}
@@ -0,0 +1,268 @@
+//! Output operations that take the physical layout of the outputs into account.
+
+use regex::Regex;
+
+use i3_ipc::reply::{Output, Rect};
+
+use crate::criteria::{self, Match};
+use crate::i3cache::I3Cache;
+use crate::ipc::{self, Ipc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+    Next,
+    Prev,
+}
+
+/// Output geometry in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub x: i64,
+    pub y: i64,
+    pub width: i64,
+    pub height: i64,
+}
+
+impl From<&Rect> for Geometry {
+    fn from(rect: &Rect) -> Geometry {
+        Geometry {
+            x: rect.x as i64,
+            y: rect.y as i64,
+            width: rect.width as i64,
+            height: rect.height as i64,
+        }
+    }
+}
+
+impl Geometry {
+    fn center(&self) -> (i64, i64) {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+
+    /// Returns true if the two geometries overlap along the axis perpendicular to `direction`,
+    /// e.g. if they share some rows of pixels when moving left or right.
+    fn overlaps(&self, other: &Geometry, direction: Direction) -> bool {
+        match direction {
+            Direction::Left | Direction::Right => {
+                self.y < other.y + other.height && other.y < self.y + self.height
+            }
+            _ => self.x < other.x + other.width && other.x < self.x + self.width,
+        }
+    }
+}
+
+pub fn parse_direction(input: &str) -> Option<Direction> {
+    match input.to_lowercase().as_str() {
+        "left" => Some(Direction::Left),
+        "right" => Some(Direction::Right),
+        "up" => Some(Direction::Up),
+        "down" => Some(Direction::Down),
+        "next" => Some(Direction::Next),
+        "prev" => Some(Direction::Prev),
+        _ => None,
+    }
+}
+
+/// Returns the index of the geometric neighbour of `from` in `direction`. Outputs that overlap
+/// `from` along the perpendicular axis are preferred, then the closest one. `Next` and `Prev` walk
+/// the outputs in reading order (top to bottom, then left to right), wrapping around.
+pub fn neighbour(geometries: &[Geometry], from: usize, direction: Direction) -> Option<usize> {
+    let (fx, fy) = geometries[from].center();
+
+    if let Direction::Next | Direction::Prev = direction {
+        let mut order: Vec<usize> = (0..geometries.len()).collect();
+        order.sort_by_key(|&i| (geometries[i].y, geometries[i].x));
+        let pos = order.iter().position(|&i| i == from).unwrap();
+        let len = order.len();
+        let next = match direction {
+            Direction::Next => order[(pos + 1) % len],
+            _ => order[(pos + len - 1) % len],
+        };
+        return if next == from { None } else { Some(next) };
+    }
+
+    geometries
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != from)
+        .filter_map(|(i, g)| {
+            let (cx, cy) = g.center();
+            // (distance along direction, distance across direction)
+            let (along, across) = match direction {
+                Direction::Left => (fx - cx, cy - fy),
+                Direction::Right => (cx - fx, cy - fy),
+                Direction::Up => (fy - cy, cx - fx),
+                _ => (cy - fy, cx - fx),
+            };
+            if along > 0 {
+                let overlaps = g.overlaps(&geometries[from], direction);
+                Some(((!overlaps, along, across.abs()), i))
+            } else {
+                None
+            }
+        })
+        .min()
+        .map(|(_, i)| i)
+}
+
+fn move_workspace(
+    matches: &clap::ArgMatches,
+    criteria: &[Match],
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    let outputs: Vec<Output> = data
+        .outputs(conn)
+        .map_err(|e| format!("{}", e))?
+        .iter()
+        .filter(|o| o.active)
+        .cloned()
+        .collect();
+    let workspaces = data.workspaces(conn).map_err(|e| format!("{}", e))?;
+    let focused = workspaces
+        .iter()
+        .find(|w| w.focused)
+        .ok_or("Unable to find focused workspace")?;
+
+    // Without any workspace criteria, move the focused workspace rather than every workspace.
+    let mut criteria = criteria.to_vec();
+    if !criteria.iter().any(|c| matches!(c, Match::Workspace(_))) {
+        criteria.push(Match::Workspace(Regex::new("__focused__").unwrap()));
+    }
+
+    let target = matches.value_of("move-workspace").unwrap();
+    let direction = parse_direction(target);
+    let geometries: Vec<Geometry> = outputs.iter().map(|o| Geometry::from(&o.rect)).collect();
+
+    let mut command = String::new();
+    let mut moved = vec![];
+    for node in criteria::matched_workspaces(conn, data, &criteria)?
+        .0
+        .iter()
+    {
+        let name = match &node.name {
+            Some(name) => name,
+            None => continue,
+        };
+        let source = match workspaces
+            .iter()
+            .find(|w| &w.name == name)
+            .and_then(|w| outputs.iter().position(|o| o.name == w.output))
+        {
+            Some(source) => source,
+            None => continue,
+        };
+        let destination = match direction {
+            Some(direction) => neighbour(&geometries, source, direction),
+            None => outputs.iter().position(|o| o.name == target),
+        };
+        let destination = match destination {
+            Some(destination) if destination != source => &outputs[destination].name,
+            Some(_) => continue,
+            None if direction.is_some() => continue,
+            None => return Err(format!("No active output named '{}'", target)),
+        };
+
+        command += &format!(
+            "workspace {}; move workspace to output {}; ",
+            criteria::i3_quote(name),
+            criteria::i3_quote(destination)
+        );
+        moved.push(name);
+    }
+
+    if command.is_empty() {
+        return Ok(());
+    }
+    // Switching to a workspace in order to move it hides the workspace that was visible on its
+    // output, so show the workspaces that were visible before, ending with the focused one.
+    let mut visible: Vec<_> = workspaces
+        .iter()
+        .filter(|w| w.visible && !moved.contains(&&w.name))
+        .collect();
+    visible.sort_by_key(|w| w.focused);
+    for workspace in visible {
+        command += &format!("workspace {}; ", criteria::i3_quote(&workspace.name));
+    }
+    match moved.last() {
+        Some(name) if matches.is_present("follow") => {
+            command += &format!("workspace {}", criteria::i3_quote(name))
+        }
+        _ => command += &format!("focus output {}", criteria::i3_quote(&focused.output)),
+    }
+    ipc::run_command(conn, &command)
+}
+
+pub fn output_subcmd(
+    matches: &clap::ArgMatches,
+    criteria: &[Match],
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    if matches.is_present("move-workspace") {
+        move_workspace(matches, criteria, conn, data)
+    } else {
+        let outputs = data.outputs(conn).map_err(|e| format!("{}", e))?;
+        for output in outputs.iter().filter(|o| o.active) {
+            let g = Geometry::from(&output.rect);
+            outln!(
+                "{} {}x{}+{}+{}{}",
+                output.name,
+                g.width,
+                g.height,
+                g.x,
+                g.y,
+                if output.primary { " primary" } else { "" }
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geometry(x: i64, y: i64, width: i64, height: i64) -> Geometry {
+        Geometry {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_neighbour() {
+        //  +---+---+
+        //  | 0 | 1 |+---+
+        //  +---+---+| 2 |
+        //  |   3   |+---+
+        //  +-------+
+        let outputs = [
+            geometry(0, 0, 1920, 1080),
+            geometry(1920, 0, 1920, 1080),
+            geometry(3840, 540, 1080, 1080),
+            geometry(0, 1080, 3840, 1080),
+        ];
+        assert_eq!(neighbour(&outputs, 0, Direction::Right), Some(1));
+        assert_eq!(neighbour(&outputs, 0, Direction::Left), None);
+        assert_eq!(neighbour(&outputs, 0, Direction::Down), Some(3));
+        assert_eq!(neighbour(&outputs, 1, Direction::Right), Some(2));
+        assert_eq!(neighbour(&outputs, 1, Direction::Down), Some(3));
+        assert_eq!(neighbour(&outputs, 2, Direction::Left), Some(1));
+        assert_eq!(neighbour(&outputs, 3, Direction::Up), Some(0));
+        assert_eq!(neighbour(&outputs, 3, Direction::Right), Some(2));
+
+        assert_eq!(neighbour(&outputs, 0, Direction::Next), Some(1));
+        assert_eq!(neighbour(&outputs, 1, Direction::Next), Some(2));
+        assert_eq!(neighbour(&outputs, 2, Direction::Next), Some(3));
+        assert_eq!(neighbour(&outputs, 3, Direction::Next), Some(0));
+        assert_eq!(neighbour(&outputs, 0, Direction::Prev), Some(3));
+        assert_eq!(neighbour(&outputs[..1], 0, Direction::Next), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! Picture-in-picture mode: a small sticky floating window in a corner of its output. The state
+//! of each window before it entered picture-in-picture mode is kept in a state file, so that it
+//! can be restored when it leaves.
+
+use serde::{Deserialize, Serialize};
+
+use i3_ipc::reply::{NodeType, Rect};
+
+use std::collections::BTreeMap;
+
+use crate::config::{Config, Pip};
+use crate::criteria;
+use crate::expr::Expr;
+use crate::float::{self, Placement};
+use crate::i3cache::{self, I3Cache};
+use crate::ipc::{self, Ipc};
+use crate::search;
+use crate::server;
+
+/// Parses a size like `480x270`.
+pub fn parse_size(input: &str) -> Result<(isize, isize), String> {
+    let mut split = input.splitn(2, 'x');
+    let mut next = || split.next().and_then(|s| s.trim().parse::<isize>().ok());
+    match (next(), next()) {
+        (Some(width), Some(height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(format!("Expected '<width>x<height>', found '{}'", input)),
+    }
+}
+
+pub fn validate_size(input: String) -> Result<(), String> {
+    parse_size(&input).map(|_| ())
+}
+
+/// Parses one of the placements of `oi3h float` that keeps the window's size.
+pub fn parse_corner(input: &str) -> Result<Placement, String> {
+    match float::parse_placement(input) {
+        Ok(placement @ Placement::Align(_, _)) => Ok(placement),
+        _ => Err(format!("Expected a corner or edge, found '{}'", input)),
+    }
+}
+
+pub fn validate_corner(input: String) -> Result<(), String> {
+    parse_corner(&input).map(|_| ())
+}
+
+/// The state of a window before it entered picture-in-picture mode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Saved {
+    floating: bool,
+    sticky: bool,
+    rect: Rect,
+}
+
+/// Maps container ids to their saved state.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PipState(BTreeMap<usize, Saved>);
+
+/// The state file, `$XDG_RUNTIME_DIR/oi3h-pip.json`.
+const STATE: &str = "-pip.json";
+
+/// Returns the geometry of a window in picture-in-picture mode on an output.
+fn pip_rect(pip: &Pip, output: &Rect) -> Rect {
+    let area = Rect {
+        x: output.x + pip.margin,
+        y: output.y + pip.margin,
+        width: output.width - 2 * pip.margin,
+        height: output.height - 2 * pip.margin,
+    };
+    let size = Rect {
+        x: 0,
+        y: 0,
+        width: pip.width,
+        height: pip.height,
+    };
+    float::place(pip.corner, &area, &size, 0, 1)
+}
+
+/// Returns the commands that put a window back the way it was.
+fn restore_command(id: usize, saved: &Saved) -> String {
+    let sticky = format!("sticky {}", if saved.sticky { "enable" } else { "disable" });
+    match saved.floating {
+        true => format!(
+            "[con_id={}] {}, resize set {} px {} px, move position {} px {} px",
+            id, sticky, saved.rect.width, saved.rect.height, saved.rect.x, saved.rect.y
+        ),
+        false => format!("[con_id={}] {}, floating disable", id, sticky),
+    }
+}
+
+pub fn pip_subcmd(
+    matches: &clap::ArgMatches,
+    expr: &Expr,
+    config: &Config,
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    let mut pip = config.pip().clone();
+    if let Some(size) = matches.value_of("size") {
+        let (width, height) = parse_size(size)?;
+        pip.width = width;
+        pip.height = height;
+    }
+    if let Some(corner) = matches.value_of("corner") {
+        pip.corner = parse_corner(corner)?;
+    }
+
+    let tree = data.full_tree(conn).map_err(|e| format!("{}", e))?;
+    let outputs = data.outputs(conn).map_err(|e| format!("{}", e))?;
+    let nodes = criteria::selected_nodes(conn, data, expr)?;
+    let mut state: PipState = server::load_state(STATE);
+    // Forget windows that have been closed since.
+    state
+        .0
+        .retain(|&id, _| search::i3_tree_find_first(tree, |n| n.id == id).is_some());
+
+    for node in nodes {
+        if let Some(saved) = state.0.remove(&node.id) {
+            ipc::run_command(conn, &restore_command(node.id, &saved))?;
+            continue;
+        }
+
+        // The position of a floating window is the position of its floating container.
+        let rect = i3cache::lineage(tree, node.id)
+            .and_then(|lineage| {
+                lineage
+                    .into_iter()
+                    .find(|n| n.node_type == NodeType::FloatingCon)
+            })
+            .unwrap_or(node)
+            .rect
+            .clone();
+        let output_name = data
+            .containing_output(conn, node.id)
+            .and_then(|o| o.name.clone())
+            .ok_or(format!("Container {} isn't on an output", node.id))?;
+        let output = outputs
+            .iter()
+            .find(|o| o.name == output_name)
+            .ok_or(format!("Unknown output: '{}'", output_name))?;
+
+        let target = pip_rect(&pip, &output.rect);
+        ipc::run_command(
+            conn,
+            &format!(
+                "[con_id={}] floating enable, sticky enable, resize set {} px {} px, move position {} px {} px",
+                node.id, target.width, target.height, target.x, target.y
+            ),
+        )?;
+        state.0.insert(
+            node.id,
+            Saved {
+                floating: criteria::is_floating(node),
+                sticky: node.sticky,
+                rect,
+            },
+        );
+    }
+    server::save_state(STATE, &state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pip() {
+        let output = Rect {
+            x: 1920,
+            y: 0,
+            width: 1920,
+            height: 1080,
+        };
+        let pip = Pip {
+            margin: 20,
+            ..Pip::default()
+        };
+        assert_eq!(
+            pip_rect(&pip, &output),
+            Rect {
+                x: 3340,
+                y: 790,
+                width: 480,
+                height: 270
+            }
+        );
+
+        let saved = Saved {
+            floating: false,
+            sticky: false,
+            rect: output,
+        };
+        assert_eq!(
+            restore_command(4, &saved),
+            "[con_id=4] sticky disable, floating disable"
+        );
+        assert_eq!(parse_size("640x360"), Ok((640, 360)));
+        assert!(parse_size("640").is_err());
+        assert!(parse_corner("grid 2x2:1").is_err());
+    }
+}
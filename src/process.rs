@@ -0,0 +1,64 @@
+//! Looks up the processes that own X11 windows.
+
+use std::process::Command;
+
+use crate::server;
+
+/// Returns the pid of the process that owns an X11 window, as advertised by the window's
+/// `_NET_WM_PID` property. This requires `xprop`, and not every client sets the property.
+pub fn window_pid(window: u32) -> Option<u32> {
+    let mut command = Command::new("xprop");
+    command.args(&["-id", &window.to_string(), "_NET_WM_PID"]);
+    // Talk to the client's display when running for a client of `oi3h server`.
+    if let Some(display) = server::env_var_os("DISPLAY") {
+        command.env("DISPLAY", display);
+    }
+    let output = command.output().ok()?;
+    // e.g. "_NET_WM_PID(CARDINAL) = 1234"
+    String::from_utf8_lossy(&output.stdout)
+        .splitn(2, '=')
+        .nth(1)?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Returns the command line of a running process.
+pub fn pid_cmdline(pid: u32) -> Option<Vec<String>> {
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let args: Vec<String> = cmdline
+        .split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    if args.is_empty() {
+        None
+    } else {
+        Some(args)
+    }
+}
+
+/// Quotes a command line so that `sh` will split it back into the same arguments.
+pub fn shell_quote(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| format!("'{}'", arg.replace('\'', r"'\''")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        let args: Vec<String> = vec!["echo".into(), "it's".into(), "a b".into()];
+        assert_eq!(shell_quote(&args), r"'echo' 'it'\''s' 'a b'");
+    }
+
+    #[test]
+    fn test_pid_cmdline() {
+        let cmdline = pid_cmdline(std::process::id()).unwrap();
+        assert!(!cmdline.is_empty());
+    }
+}
@@ -0,0 +1,245 @@
+//! Output profiles that restore workspace-to-output assignments when the set of connected outputs
+//! changes, e.g. when docking or undocking a laptop.
+
+use regex::Regex;
+
+use i3_ipc::event::{Event, Subscribe};
+use i3_ipc::I3Stream;
+
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use crate::criteria;
+use crate::i3cache::I3Cache;
+use crate::ipc::{self, Ipc};
+use crate::server;
+
+/// Maps workspaces to outputs for one particular set of connected outputs.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    /// Names of the outputs that must be active for this profile to apply, sorted.
+    pub outputs: Vec<String>,
+    /// (workspace pattern, output pattern) pairs, in order. Each workspace is moved by the first
+    /// pair that matches it. Patterns support the same special values as the `workspace=` and
+    /// `output=` criteria, e.g. `__primary__`.
+    pub mappings: Vec<(Regex, Regex)>,
+}
+
+/// Parses a profiles file. A profile starts with a `[<name>] <output> <output>...` header listing
+/// the outputs it applies to, followed by `<workspace regex> = <output regex>` lines. Empty lines
+/// and lines starting with `#` are ignored.
+pub fn parse_profiles(input: &str) -> Result<Vec<Profile>, String> {
+    let mut profiles: Vec<Profile> = vec![];
+    for (num, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            let end = line
+                .find(']')
+                .ok_or(format!("line {}: expected '[<name>] <outputs>'", num + 1))?;
+            let mut outputs: Vec<String> = line[end + 1..]
+                .split_whitespace()
+                .map(|o| o.to_string())
+                .collect();
+            if outputs.is_empty() {
+                return Err(format!("line {}: profile has no outputs", num + 1));
+            }
+            outputs.sort();
+            profiles.push(Profile {
+                name: line[1..end].trim().to_string(),
+                outputs,
+                mappings: vec![],
+            });
+            continue;
+        }
+
+        let profile = profiles
+            .last_mut()
+            .ok_or(format!("line {}: mapping outside of a profile", num + 1))?;
+        let mut token_split = line.splitn(2, '=');
+        let workspace = token_split.next().unwrap().trim();
+        let output = token_split
+            .next()
+            .ok_or(format!(
+                "line {}: expected '<workspace> = <output>'",
+                num + 1
+            ))?
+            .trim();
+        profile.mappings.push((
+            Regex::new(workspace).map_err(|e| format!("line {}: workspace: {}", num + 1, e))?,
+            Regex::new(output).map_err(|e| format!("line {}: output: {}", num + 1, e))?,
+        ));
+    }
+    Ok(profiles)
+}
+
+fn read_profiles(path: &str) -> Result<Vec<Profile>, String> {
+    let input = std::fs::read_to_string(server::resolve_path(path))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    parse_profiles(&input).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn active_outputs(conn: &mut dyn Ipc, data: &I3Cache) -> Result<Vec<String>, String> {
+    let mut outputs: Vec<String> = data
+        .outputs(conn)
+        .map_err(|e| format!("{}", e))?
+        .iter()
+        .filter(|o| o.active)
+        .map(|o| o.name.clone())
+        .collect();
+    outputs.sort();
+    Ok(outputs)
+}
+
+/// Moves every workspace to the output assigned to it by `profile`. Focus is returned to the
+/// workspace that was focused before.
+pub fn apply_profile(conn: &mut dyn Ipc, data: &I3Cache, profile: &Profile) -> Result<(), String> {
+    let workspaces = data.workspaces(conn).map_err(|e| format!("{}", e))?;
+    let focused = match workspaces.iter().find(|w| w.focused) {
+        Some(focused) => focused,
+        None => return Ok(()),
+    };
+
+    let mut claimed: Vec<usize> = vec![];
+    let mut command = String::new();
+    for (workspace_pattern, output_pattern) in profile.mappings.iter() {
+        let outputs = criteria::matched_outputs(conn, data, &[])?;
+        let target = match criteria::match_output(conn, data, outputs, output_pattern)?
+            .0
+            .first()
+            .and_then(|o| o.name.clone())
+        {
+            Some(target) => target,
+            None => continue,
+        };
+
+        let candidates = criteria::all_workspaces(criteria::matched_outputs(conn, data, &[])?);
+        for node in criteria::match_workspace(conn, data, candidates, workspace_pattern)?
+            .0
+            .iter()
+        {
+            if claimed.contains(&node.id) {
+                continue;
+            }
+            claimed.push(node.id);
+
+            let workspace = match workspaces
+                .iter()
+                .find(|w| Some(&w.name) == node.name.as_ref())
+            {
+                Some(workspace) => workspace,
+                None => continue,
+            };
+            if workspace.output != target {
+                command += &format!(
+                    "workspace {}; move workspace to output {}; ",
+                    criteria::i3_quote(&workspace.name),
+                    criteria::i3_quote(&target)
+                );
+            }
+        }
+    }
+
+    if command.is_empty() {
+        return Ok(());
+    }
+    command += &format!("workspace {}", criteria::i3_quote(&focused.name));
+    ipc::run_command(conn, &command)
+}
+
+/// Applies the first profile whose outputs match the active outputs, if any.
+fn apply_matching(conn: &mut dyn Ipc, data: &I3Cache, profiles: &[Profile]) -> Result<(), String> {
+    let active = active_outputs(conn, data)?;
+    match profiles.iter().find(|p| p.outputs == active) {
+        Some(profile) => apply_profile(conn, data, profile),
+        None => Ok(()),
+    }
+}
+
+/// Appends a profile for the active outputs that maps each workspace to its current output.
+fn save_profile(conn: &mut dyn Ipc, data: &I3Cache, path: &str, name: &str) -> Result<(), String> {
+    let active = active_outputs(conn, data)?;
+    let mut profile = format!("\n[{}] {}\n", name, active.join(" "));
+    let mut workspaces: Vec<_> = data
+        .workspaces(conn)
+        .map_err(|e| format!("{}", e))?
+        .iter()
+        .map(|w| (w.num, w.name.clone(), w.output.clone()))
+        .collect();
+    workspaces.sort();
+    for (_, workspace, output) in workspaces.iter() {
+        profile += &format!(
+            "^{}$ = ^{}$\n",
+            regex::escape(workspace),
+            regex::escape(output)
+        );
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(server::resolve_path(path))
+        .and_then(|mut f| f.write_all(profile.as_bytes()))
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn outputs_subcmd(
+    matches: &clap::ArgMatches,
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    let path = matches.value_of("profiles").unwrap();
+    if let Some(name) = matches.value_of("save") {
+        return save_profile(conn, data, path, name);
+    }
+
+    let profiles = read_profiles(path)?;
+    apply_matching(conn, data, &profiles)?;
+    if matches.is_present("once") {
+        return Ok(());
+    }
+
+    let mut events = I3Stream::conn_sub(&[Subscribe::Output]).map_err(|e| format!("{}", e))?;
+    for event in events.listen() {
+        if let Event::Output(_) = event.map_err(|e| format!("{}", e))? {
+            apply_matching(conn, &I3Cache::new(), &profiles)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profiles() {
+        let profiles = parse_profiles(
+            "# comment\n\
+             [docked] HDMI-1 eDP-1\n\
+             ^[1-5]$ = HDMI-1\n\
+             .* = eDP-1\n\
+             \n\
+             [laptop] eDP-1\n\
+             .* = __primary__\n",
+        )
+        .unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0].name, "docked");
+        assert_eq!(profiles[0].outputs, vec!["HDMI-1", "eDP-1"]);
+        assert_eq!(profiles[0].mappings.len(), 2);
+        assert_eq!(profiles[0].mappings[0].0.as_str(), "^[1-5]$");
+        assert_eq!(profiles[0].mappings[0].1.as_str(), "HDMI-1");
+        assert_eq!(profiles[1].outputs, vec!["eDP-1"]);
+        assert_eq!(profiles[1].mappings[0].1.as_str(), "__primary__");
+
+        assert!(parse_profiles(".* = eDP-1").is_err());
+        assert!(parse_profiles("[empty]").is_err());
+        assert!(parse_profiles("[docked] eDP-1\n.*").is_err());
+        assert!(parse_profiles("[docked] eDP-1\n(.* = eDP-1").is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//! Queries about the state of i3, for scripts, status bars and key bindings.
+//!
+//! Besides named queries like `fullscreen`, a query can be a format string that is printed once
+//! for every container selected by the criteria, e.g. `'{workspace}:{class} {title}'`. Fields are
+//! written in braces, and `{{` and `}}` stand for literal braces.
+
+use i3_ipc::reply::{Node, NodeType};
+
+use crate::criteria;
+use crate::expr::Expr;
+use crate::i3cache::{self, I3Cache};
+use crate::ipc::Ipc;
+use crate::search;
+
+/// Returns the fullscreen container that covers `workspace`, if any: a container in fullscreen
+/// mode on the workspace, or a container in global fullscreen mode anywhere. i3 reports
+/// workspaces themselves as fullscreen, so they are skipped.
+fn fullscreen_container<'a>(tree: &'a Node, workspace: &'a Node) -> Option<&'a Node> {
+    let is_fullscreen = |n: &Node, mode: u64| {
+        n.node_type != NodeType::Workspace && criteria::fullscreen_mode(n) >= mode
+    };
+    search::i3_tree_find_first(tree, |n| is_fullscreen(n, 2))
+        .or_else(|| search::i3_tree_find_first(workspace, |n| is_fullscreen(n, 1)))
+}
+
+/// Returns the fullscreen container on the focused workspace, if any.
+pub fn focused_fullscreen<'a>(
+    conn: &mut dyn Ipc,
+    data: &'a I3Cache,
+) -> Result<Option<&'a Node>, String> {
+    let tree = data.full_tree(conn).map_err(|e| format!("{}", e))?;
+    let workspace = data.focused_workspace(conn)?;
+    Ok(fullscreen_container(tree, workspace))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    ConId,
+    Window,
+    Class,
+    Title,
+    Workspace,
+    Output,
+    Layout,
+    Border,
+    Rect,
+    Marks,
+}
+
+fn parse_field(input: &str) -> Result<Field, String> {
+    match input {
+        "con_id" => Ok(Field::ConId),
+        "window" => Ok(Field::Window),
+        "class" => Ok(Field::Class),
+        "title" => Ok(Field::Title),
+        "workspace" => Ok(Field::Workspace),
+        "output" => Ok(Field::Output),
+        "layout" => Ok(Field::Layout),
+        "border" => Ok(Field::Border),
+        "rect" => Ok(Field::Rect),
+        "marks" => Ok(Field::Marks),
+        s => Err(format!("Unknown field: '{}'", s)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Field(Field),
+}
+
+/// Parses a format string. A lone field name is short for that field in braces.
+fn parse_format(input: &str) -> Result<Vec<Segment>, String> {
+    if let Ok(field) = parse_field(input) {
+        return Ok(vec![Segment::Field(field)]);
+    }
+
+    let mut segments = vec![];
+    let mut text = String::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().map(|&(_, c)| c) == Some('{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek().map(|&(_, c)| c) == Some('}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let end = input[pos..]
+                    .find('}')
+                    .map(|len| pos + len)
+                    .ok_or_else(|| criteria::error_at(input, pos, "Unclosed '{'".to_string()))?;
+                let field = parse_field(&input[pos + 1..end])
+                    .map_err(|e| criteria::error_at(input, pos + 1, e))?;
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::Field(field));
+                while chars.peek().map_or(false, |&(p, _)| p <= end) {
+                    chars.next();
+                }
+            }
+            '}' => return Err(criteria::error_at(input, pos, "Unmatched '}'".to_string())),
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+    Ok(segments)
+}
+
+/// Returns the value of a field of `node`. Fields that belong to an ancestor, like the workspace,
+/// are looked up along the path from the root of `tree` to `node`.
+fn field_value(tree: &Node, node: &Node, field: Field) -> String {
+    let ancestor = |node_type: NodeType| {
+        i3cache::lineage(tree, node.id)
+            .and_then(|lineage| lineage.into_iter().find(|n| n.node_type == node_type))
+            .and_then(|n| n.name.clone())
+            .unwrap_or_default()
+    };
+    let properties = node.window_properties.as_ref();
+    match field {
+        Field::ConId => node.id.to_string(),
+        Field::Window => node.window.map(|w| w.to_string()).unwrap_or_default(),
+        Field::Class => properties.and_then(|p| p.class.clone()).unwrap_or_default(),
+        Field::Title => node.name.clone().unwrap_or_default(),
+        Field::Workspace => ancestor(NodeType::Workspace),
+        Field::Output => ancestor(NodeType::Output),
+        Field::Layout => criteria::layout_name(node),
+        Field::Border => serde_json::to_value(&node.border)
+            .ok()
+            .and_then(|v| v.as_str().map(|v| v.to_string()))
+            .unwrap_or_default(),
+        Field::Rect => format!(
+            "{}x{}+{}+{}",
+            node.rect.width, node.rect.height, node.rect.x, node.rect.y
+        ),
+        Field::Marks => node.marks.0.join(","),
+    }
+}
+
+fn format_node(tree: &Node, node: &Node, format: &[Segment]) -> String {
+    format
+        .iter()
+        .map(|segment| match segment {
+            Segment::Text(text) => text.clone(),
+            Segment::Field(field) => field_value(tree, node, *field),
+        })
+        .collect()
+}
+
+pub fn validate_query(input: String) -> Result<(), String> {
+    match input.as_str() {
+        "fullscreen" => Ok(()),
+        format => parse_format(format).map(|_| ()),
+    }
+}
+
+pub fn query_subcmd(
+    matches: &clap::ArgMatches,
+    expr: &Expr,
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    match matches.value_of("query").unwrap() {
+        "fullscreen" => {
+            let mode = match focused_fullscreen(conn, data)?.map(criteria::fullscreen_mode) {
+                Some(2) => "global",
+                Some(_) => "output",
+                None => "none",
+            };
+            outln!("{}", mode);
+        }
+        format => {
+            let format = parse_format(format)?;
+            let tree = data.full_tree(conn).map_err(|e| format!("{}", e))?;
+            for node in criteria::selected_nodes(conn, data, expr)? {
+                outln!("{}", format_node(tree, node, &format));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::tree;
+
+    #[test]
+    fn test_fullscreen_container() {
+        let mut tree = tree();
+        // i3 reports the mode as a number.
+        tree.nodes[0].fullscreen_mode = serde_json::from_str("1").unwrap();
+        let found = |tree: &Node| fullscreen_container(tree, &tree.nodes[0]).map(|n| n.id);
+        assert_eq!(found(&tree), None);
+        tree.nodes[0].nodes[0].nodes[1].fullscreen_mode = tree.nodes[0].fullscreen_mode.clone();
+        assert_eq!(found(&tree), Some(5));
+    }
+
+    #[test]
+    fn test_format_node() {
+        let mut tree = tree();
+        tree.nodes[0].name = Some("1: web".to_string());
+        let window = &mut tree.nodes[0].nodes[0].nodes[1];
+        window.name = Some("vim".to_string());
+        window.marks.0 = vec!["a".to_string(), "b".to_string()];
+        window.rect.width = 800;
+        let window = tree.nodes[0].nodes[0].nodes[1].clone();
+
+        let format = |input| format_node(&tree, &window, &parse_format(input).unwrap());
+        assert_eq!(format("{workspace}:{con_id} {title}"), "1: web:5 vim");
+        assert_eq!(format("{{{marks}}} {rect}"), "{a,b} 800x0+0+0");
+        assert_eq!(format("window"), "50");
+        assert_eq!(format("{output}"), "");
+
+        assert_eq!(
+            parse_format("{class} {name}").unwrap_err(),
+            "column 10: Unknown field: 'name'"
+        );
+        assert_eq!(
+            parse_format("{class").unwrap_err(),
+            "column 1: Unclosed '{'"
+        );
+        assert!(parse_format("class}").is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! `oi3h record` saves i3 events to a file, and `oi3h replay` feeds them back into the rule
+//! engine without a live i3, so that rules can be tried out and regression-tested.
+//!
+//! A recording has one JSON object per line. Every line has the number of seconds since the
+//! recording started, and every line except the first has an event. Lines also carry the tree,
+//! workspaces and outputs whenever the recorder had to fetch them again, which is always the case
+//! for the first line. The recorder keeps an `I3Cache` up to date from events the same way the
+//! daemon does, so the replayer's cache needs the saved snapshots at exactly the same points.
+//!
+//! Criteria can also depend on state outside of i3: the urgency timestamps and the pids of
+//! windows. Lines carry the urgency timestamps whenever they changed, and the pids of windows the
+//! recorder hadn't seen before, so that replays don't depend on the machine they run on.
+
+use serde::{Deserialize, Serialize};
+
+use i3_ipc::event::{
+    BindingData, Event, ModeData, OutputData, Subscribe, WindowData, WorkspaceData,
+};
+use i3_ipc::reply::{Node, Outputs, Success, Workspaces};
+use i3_ipc::I3Stream;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::i3cache::I3Cache;
+use crate::ipc::Ipc;
+use crate::rules;
+use crate::search;
+use crate::urgency::Urgency;
+
+/// The events that can be recorded. These are the ones the rule engine knows about.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+enum RecordedEvent {
+    Workspace(Box<WorkspaceData>),
+    Output(OutputData),
+    Mode(ModeData),
+    Window(Box<WindowData>),
+    Binding(BindingData),
+}
+
+impl RecordedEvent {
+    fn from_event(event: &Event) -> Option<RecordedEvent> {
+        match event {
+            Event::Workspace(ev) => Some(RecordedEvent::Workspace(ev.clone())),
+            Event::Output(ev) => Some(RecordedEvent::Output(ev.clone())),
+            Event::Mode(ev) => Some(RecordedEvent::Mode(ev.clone())),
+            Event::Window(ev) => Some(RecordedEvent::Window(ev.clone())),
+            Event::Binding(ev) => Some(RecordedEvent::Binding(ev.clone())),
+            _ => None,
+        }
+    }
+
+    fn into_event(self) -> Event {
+        match self {
+            RecordedEvent::Workspace(ev) => Event::Workspace(ev),
+            RecordedEvent::Output(ev) => Event::Output(ev),
+            RecordedEvent::Mode(ev) => Event::Mode(ev),
+            RecordedEvent::Window(ev) => Event::Window(ev),
+            RecordedEvent::Binding(ev) => Event::Binding(ev),
+        }
+    }
+}
+
+/// One line of a recording.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Entry {
+    time: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    event: Option<RecordedEvent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tree: Option<Node>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    workspaces: Option<Workspaces>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    outputs: Option<Outputs>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    urgency: Option<Urgency>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pids: BTreeMap<u32, u32>,
+}
+
+/// Keeps a copy of everything fetched through it, so that it can be saved with the event that
+/// caused it to be fetched.
+struct Recorder<'a> {
+    conn: &'a mut dyn Ipc,
+    entry: Entry,
+    urgency: Option<Urgency>,
+    windows: HashSet<u32>,
+}
+
+impl Recorder<'_> {
+    /// Adds the urgency timestamps to the entry if they changed, and the pids of new windows.
+    fn snapshot(&mut self, data: &I3Cache) {
+        let urgency = self.conn.urgency();
+        if self.urgency.as_ref() != Some(&urgency) {
+            self.entry.urgency = Some(urgency.clone());
+            self.urgency = Some(urgency);
+        }
+
+        let tree = match data.full_tree(self) {
+            Ok(tree) => tree,
+            Err(_) => return,
+        };
+        for node in search::i3_tree_find_all(tree, |n| n.window.is_some()) {
+            let window = node.window.unwrap();
+            if self.windows.insert(window) {
+                if let Some(pid) = self.conn.window_pid(window) {
+                    self.entry.pids.insert(window, pid);
+                }
+            }
+        }
+    }
+}
+
+impl Ipc for Recorder<'_> {
+    fn get_tree(&mut self) -> io::Result<Node> {
+        let tree = self.conn.get_tree()?;
+        self.entry.tree = Some(tree.clone());
+        Ok(tree)
+    }
+
+    fn get_workspaces(&mut self) -> io::Result<Workspaces> {
+        let workspaces = self.conn.get_workspaces()?;
+        self.entry.workspaces = Some(workspaces.clone());
+        Ok(workspaces)
+    }
+
+    fn get_outputs(&mut self) -> io::Result<Outputs> {
+        let outputs = self.conn.get_outputs()?;
+        self.entry.outputs = Some(outputs.clone());
+        Ok(outputs)
+    }
+
+    fn run_command(&mut self, command: &str) -> io::Result<Vec<Success>> {
+        self.conn.run_command(command)
+    }
+}
+
+/// Answers requests from a recording. Commands are printed instead of being run.
+#[derive(Default)]
+struct Replay {
+    tree: Option<Node>,
+    workspaces: Option<Workspaces>,
+    outputs: Option<Outputs>,
+    urgency: Urgency,
+    pids: HashMap<u32, u32>,
+}
+
+impl Replay {
+    /// Takes the snapshots from a line of the recording. Urgency timestamps and pids stay valid
+    /// until they are replaced.
+    fn load(&mut self, entry: &mut Entry) {
+        self.tree = entry.tree.take();
+        self.workspaces = entry.workspaces.take();
+        self.outputs = entry.outputs.take();
+        if let Some(urgency) = entry.urgency.take() {
+            self.urgency = urgency;
+        }
+        self.pids.extend(std::mem::take(&mut entry.pids));
+    }
+}
+
+fn missing(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("the recording has no {} at this point", what),
+    )
+}
+
+impl Ipc for Replay {
+    fn get_tree(&mut self) -> io::Result<Node> {
+        self.tree.take().ok_or_else(|| missing("tree"))
+    }
+
+    fn get_workspaces(&mut self) -> io::Result<Workspaces> {
+        self.workspaces.take().ok_or_else(|| missing("workspaces"))
+    }
+
+    fn get_outputs(&mut self) -> io::Result<Outputs> {
+        self.outputs.take().ok_or_else(|| missing("outputs"))
+    }
+
+    fn run_command(&mut self, command: &str) -> io::Result<Vec<Success>> {
+        outln!("  {}", command);
+        Ok(vec![])
+    }
+
+    fn urgency(&mut self) -> Urgency {
+        self.urgency.clone()
+    }
+
+    fn window_pid(&mut self, window: u32) -> Option<u32> {
+        self.pids.get(&window).copied()
+    }
+
+    fn replaying(&self) -> bool {
+        true
+    }
+}
+
+/// The cache is only ever refetched when an event makes it stale, so that replays see the same
+/// snapshots as the recording.
+const NEVER_RESYNC: Duration = Duration::from_secs(u64::MAX);
+
+fn write_entry(file: &mut File, path: &str, entry: &Entry) -> Result<(), String> {
+    let mut line = serde_json::to_string(entry).map_err(|e| format!("{}: {}", path, e))?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn record_subcmd(matches: &clap::ArgMatches, conn: &mut dyn Ipc) -> Result<(), String> {
+    let path = matches.value_of("file").unwrap();
+    let mut file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut events = I3Stream::conn_sub(&[
+        Subscribe::Workspace,
+        Subscribe::Output,
+        Subscribe::Mode,
+        Subscribe::Window,
+        Subscribe::Binding,
+        Subscribe::Shutdown,
+    ])
+    .map_err(|e| format!("{}", e))?;
+
+    let start = Instant::now();
+    let mut data = I3Cache::new();
+    let mut recorder = Recorder {
+        conn,
+        entry: Entry::default(),
+        urgency: None,
+        windows: HashSet::new(),
+    };
+    data.prefetch(&mut recorder);
+    recorder.snapshot(&data);
+    write_entry(&mut file, path, &recorder.entry)?;
+
+    for event in events.listen() {
+        let event = event.map_err(|e| format!("{}", e))?;
+        if let Event::Shutdown(_) = event {
+            break;
+        }
+        let recorded = match RecordedEvent::from_event(&event) {
+            Some(recorded) => recorded,
+            None => continue,
+        };
+        data.update(&event, NEVER_RESYNC);
+        recorder.entry = Entry {
+            time: start.elapsed().as_secs_f64(),
+            event: Some(recorded),
+            ..Entry::default()
+        };
+        data.prefetch(&mut recorder);
+        recorder.snapshot(&data);
+        write_entry(&mut file, path, &recorder.entry)?;
+    }
+    Ok(())
+}
+
+pub fn replay_subcmd(matches: &clap::ArgMatches, config: &Config) -> Result<(), String> {
+    let path = matches.value_of("file").unwrap();
+    let rules = rules::rules_arg(matches, config)?;
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let conn = &mut Replay::default();
+    let mut data = I3Cache::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", path, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut entry: Entry =
+            serde_json::from_str(&line).map_err(|e| format!("{}: line {}: {}", path, i + 1, e))?;
+        conn.load(&mut entry);
+
+        let event = match entry.event {
+            Some(recorded) => recorded.into_event(),
+            None => {
+                data.prefetch(conn);
+                continue;
+            }
+        };
+        data.update(&event, NEVER_RESYNC);
+        data.prefetch(conn);
+        if let Some(name) = rules::event_name(&event) {
+            outln!("{:9.3} {}", entry.time, name);
+        }
+        rules::handle_event(conn, &data, &rules, &event);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{node, tree};
+
+    #[test]
+    fn test_replay_entries() {
+        let container = node(6, "con", Some(60), vec![]);
+        let first = serde_json::to_string(&Entry {
+            tree: Some(tree()),
+            ..Entry::default()
+        })
+        .unwrap();
+        let second = format!(
+            r#"{{"time":1.5,"event":{{"type":"window","payload":{{"change":"new","container":{}}}}}}}"#,
+            container
+        );
+
+        let mut conn = Replay::default();
+        let mut entry: Entry = serde_json::from_str(&first).unwrap();
+        assert!(entry.event.is_none());
+        conn.load(&mut entry);
+        assert_eq!(conn.get_tree().unwrap().id, 1);
+        assert!(conn.get_tree().is_err());
+        assert_eq!(conn.window_pid(40), None);
+
+        let mut entry: Entry = serde_json::from_str(&second).unwrap();
+        assert!(entry.tree.is_none());
+        entry.pids.insert(60, 1234);
+        conn.load(&mut entry);
+        assert_eq!(conn.window_pid(60), Some(1234));
+        let event = entry.event.unwrap().into_event();
+        assert_eq!(rules::event_name(&event).as_deref(), Some("window::new"));
+        assert!(RecordedEvent::from_event(&event).is_some());
+    }
+}
@@ -0,0 +1,215 @@
+//! Resizing of tiled containers. i3 resizes a container by moving the edge it shares with a
+//! sibling, so `resize grow left` fails for a container that has nothing to its left. These
+//! commands look at the container's ancestors and siblings to find an edge that can move.
+
+use i3_ipc::reply::{Node, NodeLayout, NodeType};
+
+use crate::criteria;
+use crate::expr::Expr;
+use crate::i3cache::{self, I3Cache};
+use crate::ipc::{self, Ipc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl Direction {
+    fn name(self) -> &'static str {
+        match self {
+            Direction::Left => "left",
+            Direction::Right => "right",
+            Direction::Up => "up",
+            Direction::Down => "down",
+        }
+    }
+
+    fn opposite(self) -> Direction {
+        match self {
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+        }
+    }
+
+    /// Returns true for the directions that point towards the start of a split container.
+    fn backwards(self) -> bool {
+        self == Direction::Left || self == Direction::Up
+    }
+
+    fn layout(self) -> NodeLayout {
+        match self {
+            Direction::Left | Direction::Right => NodeLayout::SplitH,
+            Direction::Up | Direction::Down => NodeLayout::SplitV,
+        }
+    }
+}
+
+fn parse_direction(input: &str) -> Result<Direction, String> {
+    match input {
+        "left" => Ok(Direction::Left),
+        "right" => Ok(Direction::Right),
+        "up" => Ok(Direction::Up),
+        "down" => Ok(Direction::Down),
+        _ => Err(format!("Unknown direction: '{}'", input)),
+    }
+}
+
+pub fn validate_grow(input: String) -> Result<(), String> {
+    // clap validates each value of `--grow <direction> <amount>` separately.
+    match input.parse::<u32>() {
+        Ok(_) => Ok(()),
+        Err(_) => parse_direction(&input).map(|_| ()),
+    }
+}
+
+fn parse_ratio(input: &str) -> Result<f64, String> {
+    match input.parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 && ratio < 1.0 => Ok(ratio),
+        _ => Err(format!(
+            "Expected a ratio between 0 and 1, found '{}'",
+            input
+        )),
+    }
+}
+
+pub fn validate_ratio(input: String) -> Result<(), String> {
+    parse_ratio(&input).map(|_| ())
+}
+
+/// Returns the innermost container of `lineage` that has a sibling in a split container with the
+/// given layout (any split layout if `layout` is `None`), along with that split container and the
+/// container's position in it.
+pub fn split_child<'a>(
+    lineage: &[&'a Node],
+    layout: Option<NodeLayout>,
+) -> Option<(&'a Node, &'a Node, usize)> {
+    for pair in lineage.windows(2).rev() {
+        let (parent, child) = (pair[0], pair[1]);
+        if child.node_type == NodeType::Workspace {
+            break;
+        }
+        let split = match &layout {
+            Some(layout) => parent.layout == *layout,
+            None => parent.layout == NodeLayout::SplitH || parent.layout == NodeLayout::SplitV,
+        };
+        if split && parent.nodes.len() > 1 {
+            if let Some(position) = parent.nodes.iter().position(|n| n.id == child.id) {
+                return Some((parent, child, position));
+            }
+        }
+    }
+    None
+}
+
+/// Returns the container to resize and the direction to grow it in, so that it grows by moving
+/// the edge facing `direction` if there is a sibling on that side, or the opposite edge otherwise.
+fn grow_target<'a>(lineage: &[&'a Node], direction: Direction) -> Option<(&'a Node, Direction)> {
+    let node = *lineage.last()?;
+    if lineage.iter().any(|n| n.node_type == NodeType::FloatingCon) {
+        // Floating windows can grow in any direction.
+        return Some((node, direction));
+    }
+    let (parent, child, position) = split_child(lineage, Some(direction.layout()))?;
+    let has_sibling = match direction.backwards() {
+        true => position > 0,
+        false => position + 1 < parent.nodes.len(),
+    };
+    match has_sibling {
+        true => Some((child, direction)),
+        false => Some((child, direction.opposite())),
+    }
+}
+
+pub fn resize_subcmd(
+    matches: &clap::ArgMatches,
+    expr: &Expr,
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    let tree = data.full_tree(conn).map_err(|e| format!("{}", e))?;
+    let nodes = criteria::selected_nodes(conn, data, expr)?;
+
+    for node in nodes {
+        let lineage = i3cache::lineage(tree, node.id).ok_or("Unable to find container in tree")?;
+        let command = if let Some(mut values) = matches.values_of("grow") {
+            let direction = parse_direction(values.next().unwrap())?;
+            let amount: u32 = values
+                .next()
+                .unwrap()
+                .parse()
+                .map_err(|_| "Expected '--grow <direction> <amount>'".to_string())?;
+            let (target, direction) = grow_target(&lineage, direction).ok_or(format!(
+                "Container {} has no siblings to take space from",
+                node.id
+            ))?;
+            format!(
+                "[con_id={}] resize grow {} {} px or {} ppt",
+                target.id,
+                direction.name(),
+                amount,
+                amount
+            )
+        } else {
+            // --ratio
+            let ratio = parse_ratio(matches.value_of("ratio").unwrap())?;
+            let (parent, target, _) = split_child(&lineage, None).ok_or(format!(
+                "Container {} isn't in a split container with other containers",
+                node.id
+            ))?;
+            let dimension = match parent.layout {
+                NodeLayout::SplitH => "width",
+                _ => "height",
+            };
+            format!(
+                "[con_id={}] resize set {} {} ppt",
+                target.id,
+                dimension,
+                (ratio * 100.0).round()
+            )
+        };
+        ipc::run_command(conn, &command)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::tree;
+
+    #[test]
+    fn test_grow_target() {
+        // Windows 4 and 5 are side by side in container 3.
+        let mut tree = tree();
+        let target = |tree: &Node, id, direction| {
+            let lineage = i3cache::lineage(tree, id).unwrap();
+            grow_target(&lineage, direction).map(|(n, d)| (n.id, d))
+        };
+        assert_eq!(
+            target(&tree, 4, Direction::Right),
+            Some((4, Direction::Right))
+        );
+        assert_eq!(
+            target(&tree, 4, Direction::Left),
+            Some((4, Direction::Right))
+        );
+        assert_eq!(
+            target(&tree, 5, Direction::Right),
+            Some((5, Direction::Left))
+        );
+        assert_eq!(target(&tree, 5, Direction::Up), None);
+
+        // Stacked vertically, window 4 has to grow at its bottom edge.
+        tree.nodes[0].nodes[0].layout = NodeLayout::SplitV;
+        assert_eq!(target(&tree, 4, Direction::Up), Some((4, Direction::Down)));
+        assert_eq!(target(&tree, 4, Direction::Left), None);
+
+        assert!(parse_ratio("0.6").is_ok());
+        assert!(parse_ratio("1.5").is_err());
+    }
+}
@@ -0,0 +1,412 @@
+//! A rule engine that runs commands in response to i3 events.
+//!
+//! Rules are written one per line:
+//! ```text
+//! on <event>[::<change>] [where <criteria>] do <action>
+//! ```
+//! `<event>` is one of `window`, `workspace`, `output`, `mode` or `binding`, and `<change>` is the
+//! `change` field of the event (e.g. `window::new`, `workspace::focus`, or the mode name for `mode`
+//! events). The criteria use the same syntax as `--criteria`, and are evaluated against the
+//! event's container (the window for `window` events, the current workspace for `workspace`
+//! events). Rules with criteria never match events that don't have a container.
+//!
+//! The action is either an i3 command, whose `;`-separated commands are each run on the event's
+//! container unless they have their own criteria, or `oi3h <args>`, which runs oi3h through `sh`
+//! with `OI3H_EVENT` and `OI3H_CON_ID` set in its environment.
+
+use serde::Serialize;
+
+use i3_ipc::event::{Event, Subscribe};
+use i3_ipc::reply::Node;
+
+use std::process::Command;
+
+use crate::config::{self, Config};
+use crate::criteria::{self, Match};
+use crate::i3cache::{self, I3Cache};
+use crate::ipc::{self, Ipc};
+use crate::process;
+use crate::urgency;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Window,
+    Workspace,
+    Output,
+    Mode,
+    Binding,
+}
+
+fn parse_event_kind(input: &str) -> Result<EventKind, String> {
+    match input {
+        "window" => Ok(EventKind::Window),
+        "workspace" => Ok(EventKind::Workspace),
+        "output" => Ok(EventKind::Output),
+        "mode" => Ok(EventKind::Mode),
+        "binding" => Ok(EventKind::Binding),
+        s => Err(format!("Unknown event: '{}'", s)),
+    }
+}
+
+impl EventKind {
+    fn subscription(self) -> Subscribe {
+        match self {
+            EventKind::Window => Subscribe::Window,
+            EventKind::Workspace => Subscribe::Workspace,
+            EventKind::Output => Subscribe::Output,
+            EventKind::Mode => Subscribe::Mode,
+            EventKind::Binding => Subscribe::Binding,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// An i3 command.
+    I3(String),
+    /// Arguments for a nested oi3h invocation, still quoted for `sh`.
+    Oi3h(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub event: EventKind,
+    pub change: Option<String>,
+    pub criteria: Vec<Match>,
+    pub action: Action,
+}
+
+/// Returns the offset just after `word`, if `input` has it as a whole word at byte offset `pos`.
+fn keyword(input: &str, pos: usize, word: &str) -> Option<usize> {
+    match input[pos..].strip_prefix(word)?.chars().next() {
+        Some(c) if !c.is_whitespace() => None,
+        _ => Some(pos + word.len()),
+    }
+}
+
+/// Parses a single rule, e.g. `on window::new where class=mpv do floating enable`. The criteria
+/// are read one at a time up to `do`, so quoted values may contain ` do `.
+pub fn parse_rule(input: &str) -> Result<Rule, String> {
+    let input = input.trim();
+    let mut pos = keyword(input, 0, "on").ok_or("Expected rule to start with 'on'")?;
+    pos = criteria::skip_whitespace(input, pos);
+
+    let spec_end = input[pos..]
+        .find(char::is_whitespace)
+        .map_or(input.len(), |len| pos + len);
+    let mut spec_split = input[pos..spec_end].splitn(2, "::");
+    let event = parse_event_kind(spec_split.next().unwrap())?;
+    let change = spec_split.next().map(|c| c.to_string());
+    pos = criteria::skip_whitespace(input, spec_end);
+
+    let mut criteria = vec![];
+    if let Some(end) = keyword(input, pos, "where") {
+        pos = criteria::skip_whitespace(input, end);
+        while pos < input.len() && keyword(input, pos, "do").is_none() {
+            let (criterion, end) = criteria::read_criterion(input, pos, &[])
+                .map_err(|(p, e)| criteria::error_at(input, p, e))?;
+            criteria.extend(criterion);
+            pos = criteria::skip_whitespace(input, end);
+        }
+    }
+
+    let action = match keyword(input, pos, "do") {
+        Some(end) => input[end..].trim(),
+        None if pos == input.len() => return Err("Expected 'do <action>'".to_string()),
+        None => {
+            return Err(format!(
+                "Expected 'where' or 'do', found '{}'",
+                input[pos..].split_whitespace().next().unwrap()
+            ))
+        }
+    };
+    let action = match action {
+        "" => return Err("Expected an action after 'do'".to_string()),
+        a if a == "oi3h" || a.starts_with("oi3h ") => Action::Oi3h(a[4..].trim().to_string()),
+        a => Action::I3(a.to_string()),
+    };
+
+    Ok(Rule {
+        event,
+        change,
+        criteria,
+        action,
+    })
+}
+
+/// Parses a rules file. Empty lines and lines starting with `#` are ignored.
+pub fn parse_rules(input: &str) -> Result<Vec<Rule>, String> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim().starts_with('#'))
+        .map(|(num, line)| parse_rule(line).map_err(|e| format!("line {}: {}", num + 1, e)))
+        .collect()
+}
+
+pub fn read_rules(path: &str) -> Result<Vec<Rule>, String> {
+    let input = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    parse_rules(&input).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Returns the i3 event subscriptions needed by a set of rules, along with the window, workspace
+/// and output events that keep the daemon's cache up to date.
+pub fn subscriptions(rules: &[Rule]) -> Vec<Subscribe> {
+    let mut kinds: Vec<EventKind> =
+        vec![EventKind::Window, EventKind::Workspace, EventKind::Output];
+    for rule in rules.iter() {
+        if !kinds.contains(&rule.event) {
+            kinds.push(rule.event);
+        }
+    }
+    kinds.into_iter().map(|k| k.subscription()).collect()
+}
+
+/// Returns the name i3 uses for the `change` field of an event.
+fn change_name<T: Serialize>(change: &T) -> String {
+    serde_json::to_value(change)
+        .ok()
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_default()
+}
+
+/// Returns the kind, `change` field, and container (if any) of an event.
+fn describe(event: &Event) -> Option<(EventKind, String, Option<&Node>)> {
+    match event {
+        Event::Window(ev) => Some((
+            EventKind::Window,
+            change_name(&ev.change),
+            Some(&ev.container),
+        )),
+        Event::Workspace(ev) => Some((
+            EventKind::Workspace,
+            change_name(&ev.change),
+            ev.current.as_ref(),
+        )),
+        Event::Output(ev) => Some((EventKind::Output, change_name(&ev.change), None)),
+        Event::Mode(ev) => Some((EventKind::Mode, ev.change.clone(), None)),
+        Event::Binding(ev) => Some((EventKind::Binding, change_name(&ev.change), None)),
+        _ => None,
+    }
+}
+
+/// Splits `;`-chained i3 commands, leaving any `;` inside quoted arguments alone.
+fn split_commands(command: &str) -> Vec<&str> {
+    let mut commands = vec![];
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in command.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                commands.push(command[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    commands.push(command[start..].trim());
+    commands.retain(|c| !c.is_empty());
+    commands
+}
+
+/// Runs an i3 command on a container. i3 only applies criteria up to the next `;`, so every
+/// `;`-separated command that doesn't have criteria of its own is given them.
+fn scope_command(command: &str, con_id: usize) -> String {
+    split_commands(command)
+        .into_iter()
+        .map(|c| match c.starts_with('[') {
+            true => c.to_string(),
+            false => format!("[con_id={}] {}", con_id, c),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn run_action(
+    conn: &mut dyn Ipc,
+    action: &Action,
+    event: &str,
+    container: Option<&Node>,
+) -> Result<(), String> {
+    match action {
+        Action::I3(command) => match container {
+            Some(con) => ipc::run_command(conn, &scope_command(command, con.id)),
+            None => ipc::run_command(conn, command),
+        },
+        Action::Oi3h(args) if conn.replaying() => {
+            outln!("  oi3h {}", args);
+            Ok(())
+        }
+        Action::Oi3h(args) => {
+            let exe = std::env::current_exe().map_err(|e| format!("{}", e))?;
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(format!(
+                    "{} {}",
+                    process::shell_quote(&[exe.to_string_lossy().into_owned()]),
+                    args
+                ))
+                .env("OI3H_EVENT", event);
+            if let Some(con) = container {
+                command.env("OI3H_CON_ID", con.id.to_string());
+            }
+            let status = command.status().map_err(|e| format!("{}", e))?;
+            if status.success() {
+                Ok(())
+            } else {
+                Err(format!("oi3h {}: {}", args, status))
+            }
+        }
+    }
+}
+
+fn format_event_name(kind: EventKind, change: &str) -> String {
+    format!(
+        "{}::{}",
+        match kind {
+            EventKind::Window => "window",
+            EventKind::Workspace => "workspace",
+            EventKind::Output => "output",
+            EventKind::Mode => "mode",
+            EventKind::Binding => "binding",
+        },
+        change
+    )
+}
+
+/// Returns the name of an event as used in rules, e.g. `window::new`.
+pub fn event_name(event: &Event) -> Option<String> {
+    describe(event).map(|(kind, change, _)| format_event_name(kind, &change))
+}
+
+/// Runs the action of every rule that matches `event`. Errors from individual actions are
+/// reported on stderr so that one failing rule doesn't stop the others.
+pub fn handle_event(conn: &mut dyn Ipc, data: &I3Cache, rules: &[Rule], event: &Event) {
+    let (kind, change, container) = match describe(event) {
+        Some(description) => description,
+        None => return,
+    };
+    let event_name = format_event_name(kind, &change);
+
+    for rule in rules.iter() {
+        if rule.event != kind || rule.change.as_ref().map_or(false, |c| c != &change) {
+            continue;
+        }
+        if !rule.criteria.is_empty() {
+            match container {
+                Some(con) if criteria::node_matches_all(conn, data, con, &rule.criteria) => {}
+                _ => continue,
+            }
+        }
+        if let Err(e) = run_action(conn, &rule.action, &event_name, container) {
+            eprintln!("{}: {}", event_name, e);
+        }
+    }
+}
+
+/// Reads the rules given with `--rules`, or the default rules from the config file.
+pub fn rules_arg(matches: &clap::ArgMatches, config: &Config) -> Result<Vec<Rule>, String> {
+    match matches.value_of("rules") {
+        Some(rules) => match config::reference(rules) {
+            Some(name) => Ok(config.rules(name)?.to_vec()),
+            None => read_rules(rules),
+        },
+        None => Ok(config
+            .default_rules()
+            .ok_or("No rules given, and the config file has no default rules")?
+            .to_vec()),
+    }
+}
+
+pub fn daemon_subcmd(
+    matches: &clap::ArgMatches,
+    config: &Config,
+    conn: &mut dyn Ipc,
+) -> Result<(), String> {
+    let rules = rules_arg(matches, config)?;
+    if rules.is_empty() {
+        return Ok(());
+    }
+
+    let resync = i3cache::resync_interval(matches)?;
+    let mut data = I3Cache::new();
+    let events = i3cache::listen(&subscriptions(&rules))?;
+    while let Some(event) = data.next_event(conn, &events, resync) {
+        let event = event.map_err(|e| format!("{}", e))?;
+        data.update(&event, resync);
+        urgency::record(&event);
+        if matches.is_present("check") {
+            i3cache::report_drift(conn, &data);
+        }
+        handle_event(conn, &data, &rules, &event);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rule() {
+        let rule = parse_rule("on window::new where class=^mpv$ floating do border none").unwrap();
+        assert_eq!(rule.event, EventKind::Window);
+        assert_eq!(rule.change.as_deref(), Some("new"));
+        assert_eq!(rule.criteria.len(), 2);
+        assert_eq!(rule.action, Action::I3("border none".to_string()));
+
+        let rule = parse_rule("on workspace do oi3h workspace-names --once").unwrap();
+        assert_eq!(rule.event, EventKind::Workspace);
+        assert_eq!(rule.change, None);
+        assert!(rule.criteria.is_empty());
+        assert_eq!(
+            rule.action,
+            Action::Oi3h("workspace-names --once".to_string())
+        );
+
+        let rule = parse_rule("  on mode::resize   do   bar mode invisible ").unwrap();
+        assert_eq!(rule.event, EventKind::Mode);
+        assert_eq!(rule.change.as_deref(), Some("resize"));
+        assert_eq!(rule.action, Action::I3("bar mode invisible".to_string()));
+
+        assert!(parse_rule("window::new do kill").is_err());
+        assert!(parse_rule("on tick do kill").is_err());
+        assert!(parse_rule("on window::new where class=mpv").is_err());
+        assert!(parse_rule("on window::new where nonsense do kill").is_err());
+        assert!(parse_rule("on window::new class=mpv do kill").is_err());
+        assert!(parse_rule("on window::new do").is_err());
+
+        let rule = parse_rule(r#"on window::title where title="to do" do mark todo"#).unwrap();
+        assert!(matches!(&rule.criteria[0], Match::Title(r) if r.as_str() == "to do"));
+        assert_eq!(rule.action, Action::I3("mark todo".to_string()));
+        assert_eq!(
+            parse_rule("on window where class=mpv bogus do kill").unwrap_err(),
+            "column 27: Unknown criteria: 'bogus'"
+        );
+    }
+
+    #[test]
+    fn test_scope_command() {
+        assert_eq!(
+            scope_command("floating enable, sticky enable; [class=x] kill", 4),
+            "[con_id=4] floating enable, sticky enable; [class=x] kill"
+        );
+        assert_eq!(
+            scope_command(r#"mark "a;b"; border none;"#, 4),
+            r#"[con_id=4] mark "a;b"; [con_id=4] border none"#
+        );
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = parse_rules("# comment\n\non window::new do border none\n").unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(
+            parse_rules("on window do kill\non nothing do kill").unwrap_err(),
+            "line 2: Unknown event: 'nothing'"
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! `oi3h server` keeps an `I3Cache` warm between invocations, so that one-shot commands don't
+//! have to connect to i3 and fetch the tree themselves. Commands that find a running server send
+//! it their arguments over a unix socket, and print the output it sends back.
+//!
+//! Each connection carries one JSON `Request`, which is answered with one JSON `Response`. A
+//! thread listening for i3 events marks the cache as stale and wakes the server with a `Refresh`
+//! request, so that the new tree is fetched before the next client needs it.
+//!
+//! Commands are run with the client's working directory and environment, which are only seen
+//! through `resolve_path` and `env_var_os`: the server's own are left alone.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use i3_ipc::event::{Event, Subscribe};
+use i3_ipc::I3Stream;
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::Permissions;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::i3cache::I3Cache;
+use crate::ipc::{self, Ipc};
+use crate::urgency;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    /// Run oi3h with these arguments (starting with the program name), in this directory and
+    /// environment.
+    Run {
+        cwd: PathBuf,
+        env: HashMap<String, String>,
+        args: Vec<String>,
+    },
+    /// The i3 state has changed.
+    Refresh,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Response {
+    stdout: String,
+    stderr: String,
+    status: i32,
+}
+
+/// The working directory and environment of the client a command is being run for.
+struct Client {
+    cwd: PathBuf,
+    env: HashMap<String, String>,
+}
+
+thread_local! {
+    /// Output of the command that is being run for a client, if any.
+    static CAPTURED: RefCell<Option<String>> = RefCell::new(None);
+    /// The client a command is being run for, if any.
+    static CLIENT: RefCell<Option<Client>> = RefCell::new(None);
+}
+
+/// Returns an environment variable, taken from the client's environment if a command is being run
+/// for a client.
+pub fn env_var_os(name: &str) -> Option<OsString> {
+    CLIENT.with(|client| match client.borrow().as_ref() {
+        Some(client) => client.env.get(name).map(OsString::from),
+        None => std::env::var_os(name),
+    })
+}
+
+/// Resolves a path given on the command line. Relative paths are relative to the client's working
+/// directory if a command is being run for a client.
+pub fn resolve_path(path: &str) -> PathBuf {
+    CLIENT.with(|client| match client.borrow().as_ref() {
+        Some(client) => client.cwd.join(path),
+        None => PathBuf::from(path),
+    })
+}
+
+/// Prints a line to stdout, or captures it if a command is being run for a client.
+pub fn print(line: String) {
+    CAPTURED.with(|captured| match captured.borrow_mut().as_mut() {
+        Some(output) => {
+            output.push_str(&line);
+            output.push('\n');
+        }
+        None => println!("{}", line),
+    })
+}
+
+/// Returns `$XDG_RUNTIME_DIR/oi3h<suffix>`, falling back to a per-user file in the temp directory.
+pub fn runtime_path(suffix: &str) -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join(format!("oi3h{}", suffix)),
+        None => std::env::temp_dir().join(format!(
+            "oi3h-{}{}",
+            std::env::var("USER").unwrap_or_default(),
+            suffix
+        )),
+    }
+}
+
+/// Reads the state file at `runtime_path(suffix)`. A missing or unreadable state file is treated
+/// as empty.
+pub fn load_state<T: DeserializeOwned + Default>(suffix: &str) -> T {
+    std::fs::read_to_string(runtime_path(suffix))
+        .ok()
+        .and_then(|input| serde_json::from_str(&input).ok())
+        .unwrap_or_default()
+}
+
+/// Replaces the state file at `runtime_path(suffix)` in one step, so that other oi3h processes
+/// never read it half written.
+pub fn save_state<T: Serialize>(suffix: &str, state: &T) -> Result<(), String> {
+    let path = runtime_path(suffix);
+    let temp = runtime_path(&format!("{}.{}", suffix, std::process::id()));
+    let output = serde_json::to_string(state).map_err(|e| format!("{}", e))?;
+    std::fs::write(&temp, output)
+        .and_then(|_| std::fs::rename(&temp, &path))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Returns `$XDG_RUNTIME_DIR/oi3h.sock`, falling back to a per-user socket in the temp directory.
+pub fn socket_path() -> PathBuf {
+    runtime_path(".sock")
+}
+
+/// Returns whether a command can be run by the server. Commands that keep running, like the
+/// daemons, are always run directly.
+pub fn forwardable(matches: &clap::ArgMatches) -> bool {
+    !matches.is_present("no-server") && runs_once(matches)
+}
+
+/// Returns whether a command acts on the current state of i3 and exits. Commands that keep
+/// running don't, and neither do replays, which don't talk to i3 at all.
+pub fn runs_once(matches: &clap::ArgMatches) -> bool {
+    match matches.subcommand() {
+        ("server", _) | ("daemon", _) | ("record", _) | ("replay", _) => false,
+        ("workspace-names", Some(m)) => m.is_present("once"),
+        ("outputs", Some(m)) => m.is_present("once") || m.is_present("save"),
+        _ => true,
+    }
+}
+
+fn exchange(mut stream: UnixStream, request: &Request) -> io::Result<Response> {
+    serde_json::to_writer(&mut stream, request)?;
+    stream.shutdown(Shutdown::Write)?;
+    Ok(serde_json::from_reader(stream)?)
+}
+
+/// Returns true if `path` belongs to the current user. The fallback socket lives in the shared
+/// temp directory, where anyone could have created it.
+fn owned_by_us(path: &Path) -> bool {
+    match (std::fs::metadata(path), std::fs::metadata("/proc/self")) {
+        (Ok(socket), Ok(process)) => socket.uid() == process.uid(),
+        _ => false,
+    }
+}
+
+/// Sends a command to the server and prints its output. Returns the command's exit status, or
+/// `None` if no server is running.
+pub fn forward(args: &[String]) -> Option<i32> {
+    let path = socket_path();
+    if !owned_by_us(&path) {
+        return None;
+    }
+    let stream = UnixStream::connect(&path).ok()?;
+    let request = Request::Run {
+        cwd: std::env::current_dir().unwrap_or_default(),
+        env: std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect(),
+        args: args.to_vec(),
+    };
+    match exchange(stream, &request) {
+        Ok(response) => {
+            print!("{}", response.stdout);
+            eprint!("{}", response.stderr);
+            Some(response.status)
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            Some(1)
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "panicked".to_string(),
+        },
+    }
+}
+
+/// Runs a command for a client, capturing its output. Panics are caught so that a failing command
+/// doesn't take the server down with it.
+fn run(client: Client, args: &[String], conn: &mut dyn Ipc, data: &I3Cache) -> Response {
+    CAPTURED.with(|captured| captured.replace(Some(String::new())));
+    CLIENT.with(|current| current.replace(Some(client)));
+    let result = crate::app()
+        .get_matches_from_safe(args)
+        .map_err(|e| e.message)
+        .and_then(|matches| {
+            panic::catch_unwind(AssertUnwindSafe(|| crate::run(&matches, conn, data)))
+                .unwrap_or_else(|panic| Err(panic_message(panic)))
+        });
+    CLIENT.with(|current| current.replace(None));
+    let stdout = CAPTURED.with(|captured| captured.replace(None).unwrap_or_default());
+
+    match result {
+        Ok(()) => Response {
+            stdout,
+            ..Response::default()
+        },
+        Err(e) => Response {
+            stdout,
+            stderr: format!("{}\n", e),
+            status: 1,
+        },
+    }
+}
+
+pub fn server_subcmd(_matches: &clap::ArgMatches, conn: &mut dyn Ipc) -> Result<(), String> {
+    let path = socket_path();
+    if UnixStream::connect(&path).is_ok() {
+        return Err(format!("{}: a server is already running", path.display()));
+    }
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)
+        .and_then(|listener| {
+            std::fs::set_permissions(&path, Permissions::from_mode(0o600)).map(|_| listener)
+        })
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let stale = Arc::new(AtomicBool::new(false));
+    let mut events = I3Stream::conn_sub(&[
+        Subscribe::Window,
+        Subscribe::Workspace,
+        Subscribe::Output,
+        Subscribe::Shutdown,
+    ])
+    .map_err(|e| format!("{}", e))?;
+    {
+        let stale = Arc::clone(&stale);
+        let path = path.clone();
+        std::thread::spawn(move || {
+            for event in events.listen() {
+                match event {
+                    Ok(Event::Shutdown(_)) | Err(_) => break,
+                    Ok(event) => {
+                        // Mark the cache as stale first, so that no command is answered from it
+                        // while the event is being handled.
+                        stale.store(true, Ordering::SeqCst);
+                        urgency::record(&event);
+                        let _ = UnixStream::connect(&path)
+                            .and_then(|stream| exchange(stream, &Request::Refresh));
+                    }
+                }
+            }
+            // i3 is exiting or restarting, and the cache can't be kept up to date without it.
+            let _ = std::fs::remove_file(&path);
+            std::process::exit(0);
+        });
+    }
+
+    let mut data = I3Cache::new();
+    data.prefetch(conn);
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let request: Request = match serde_json::from_reader(&mut stream) {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        // Checked right before every command, since a client may get here before the `Refresh`
+        // request sent for an event.
+        if stale.swap(false, Ordering::SeqCst) {
+            data = I3Cache::new();
+        }
+        let commands_run = ipc::commands_run();
+        let response = match request {
+            Request::Run { cwd, env, args } => run(Client { cwd, env }, &args, conn, &data),
+            Request::Refresh => Response::default(),
+        };
+        if let Err(e) = serde_json::to_writer(&mut stream, &response) {
+            eprintln!("{}: {}", path.display(), e);
+        }
+        drop(stream);
+
+        // The events caused by our own commands may not have arrived yet.
+        if ipc::commands_run() != commands_run {
+            data = I3Cache::new();
+        }
+        data.prefetch(conn);
+    }
+    Ok(())
+}
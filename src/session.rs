@@ -0,0 +1,271 @@
+//! Saves the state of every output, workspace and window so that it can be rebuilt after i3 is
+//! restarted or the machine is rebooted.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use i3_ipc::reply::{Node, Output, Outputs, Rect};
+
+use crate::criteria;
+use crate::i3cache::I3Cache;
+use crate::ipc::{self, Ipc};
+use crate::layout;
+use crate::process;
+use crate::search;
+use crate::server;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionOutput {
+    pub name: String,
+    pub primary: bool,
+    pub rect: Rect,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionWindow {
+    pub class: Option<String>,
+    pub instance: Option<String>,
+    pub title: Option<String>,
+    /// The process that owns the window, so that a program with several windows is only launched
+    /// once.
+    pub pid: Option<u32>,
+    /// Command line of the process that owns the window, if it could be found.
+    pub command: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionWorkspace {
+    pub name: String,
+    pub output: String,
+    pub focused: bool,
+    pub visible: bool,
+    /// The workspace contents in `append_layout` format.
+    pub layout: Vec<Value>,
+    pub windows: Vec<SessionWindow>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub outputs: Vec<SessionOutput>,
+    pub workspaces: Vec<SessionWorkspace>,
+}
+
+/// Describes the program behind a window. Marks and floating geometry are part of the workspace
+/// layout, which reapplies them when the window is swallowed.
+fn session_window(conn: &mut dyn Ipc, data: &I3Cache, window: &Node) -> SessionWindow {
+    let properties = window.window_properties.as_ref();
+    let pid = window.window.and_then(|w| data.window_pid(conn, w));
+    SessionWindow {
+        class: properties.and_then(|p| p.class.clone()),
+        instance: properties.and_then(|p| p.instance.clone()),
+        title: properties.and_then(|p| p.title.clone()),
+        pid,
+        command: pid.and_then(process::pid_cmdline),
+    }
+}
+
+pub fn save_session(
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+    swallow: &[&str],
+) -> Result<Session, String> {
+    let outputs = data
+        .outputs(conn)
+        .map_err(|e| format!("{}", e))?
+        .iter()
+        .filter(|o| o.active)
+        .map(|o| SessionOutput {
+            name: o.name.clone(),
+            primary: o.primary,
+            rect: o.rect.clone(),
+        })
+        .collect();
+
+    let mut workspaces = vec![];
+    for workspace in data.workspaces(conn).map_err(|e| format!("{}", e))?.iter() {
+        let node = layout::find_workspace(conn, data, &workspace.name)?;
+        let windows = search::i3_tree_find_all(node, |n| n.window.is_some())
+            .iter()
+            .map(|w| session_window(conn, data, w))
+            .collect();
+        workspaces.push(SessionWorkspace {
+            name: workspace.name.clone(),
+            output: workspace.output.clone(),
+            focused: workspace.focused,
+            visible: workspace.visible,
+            layout: layout::layout_workspace(node, swallow),
+            windows,
+        });
+    }
+
+    Ok(Session {
+        outputs,
+        workspaces,
+    })
+}
+
+/// Returns the active output that stands in for a saved one: the output with the same name, or
+/// else one with the same position and size (e.g. a monitor plugged into a different port), or
+/// else the primary output if the saved one was primary.
+fn restore_output<'a>(session: &Session, name: &str, outputs: &'a Outputs) -> Option<&'a Output> {
+    let active = || outputs.iter().filter(|o| o.active);
+    if let Some(output) = active().find(|o| o.name == name) {
+        return Some(output);
+    }
+    let saved = session.outputs.iter().find(|o| o.name == name)?;
+    active()
+        .find(|o| o.rect == saved.rect)
+        .or_else(|| active().find(|o| saved.primary && o.primary))
+}
+
+/// Writes a workspace layout to a new file in the runtime directory, for `append_layout`. The file
+/// must not exist yet, so that nothing planted there beforehand is written through.
+fn write_layout(index: usize, layout: &[Value]) -> Result<String, String> {
+    let path = server::runtime_path(&format!("-session-{}-{}.json", std::process::id(), index));
+    let path_str = path.to_string_lossy().into_owned();
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut f| f.write_all(layout::format_layout(layout).as_bytes()))
+        .map_err(|e| format!("{}: {}", path_str, e))?;
+    Ok(path_str)
+}
+
+/// Rebuilds a saved session: every workspace is recreated from its layout on its original output,
+/// or on the output that took its place (see `restore_output`), the programs that owned its windows are relaunched unless
+/// `exec` is false, and the previously visible and focused workspaces are shown again. A program
+/// that owned several windows is only launched once.
+pub fn restore_session(
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+    session: &Session,
+    exec: bool,
+) -> Result<(), String> {
+    let outputs = data.outputs(conn).map_err(|e| format!("{}", e))?;
+    let mut launched = HashSet::new();
+
+    for (index, workspace) in session.workspaces.iter().enumerate() {
+        if !workspace.layout.is_empty() {
+            let path = write_layout(index, &workspace.layout)?;
+            let restored = layout::restore_layout(conn, &workspace.name, &path, None);
+            let _ = std::fs::remove_file(&path);
+            restored?;
+        } else {
+            ipc::run_command(
+                conn,
+                &format!("workspace {}", criteria::i3_quote(&workspace.name)),
+            )?;
+        }
+
+        if let Some(output) = restore_output(session, &workspace.output, &outputs) {
+            ipc::run_command(
+                conn,
+                &format!(
+                    "move workspace to output {}",
+                    criteria::i3_quote(&output.name)
+                ),
+            )?;
+        }
+
+        if exec {
+            for window in workspace.windows.iter() {
+                if window.pid.map_or(false, |pid| !launched.insert(pid)) {
+                    continue;
+                }
+                if let Some(command) = &window.command {
+                    ipc::exec(conn, &process::shell_quote(command))?;
+                }
+            }
+        }
+    }
+
+    // Show the visible workspaces again, ending with the focused one.
+    let mut visible: Vec<&SessionWorkspace> =
+        session.workspaces.iter().filter(|w| w.visible).collect();
+    visible.sort_by_key(|w| w.focused);
+    for workspace in visible.iter() {
+        ipc::run_command(
+            conn,
+            &format!("workspace {}", criteria::i3_quote(&workspace.name)),
+        )?;
+    }
+    Ok(())
+}
+
+pub fn session_subcmd(
+    matches: &clap::ArgMatches,
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    match matches.subcommand() {
+        ("save", Some(save_matches)) => {
+            let path = save_matches.value_of("file").unwrap();
+            let swallow: Vec<&str> = save_matches.values_of("swallow").unwrap().collect();
+            let session = save_session(conn, data, &swallow)?;
+            let output = serde_json::to_string_pretty(&session).map_err(|e| format!("{}", e))?;
+            std::fs::write(server::resolve_path(path), output)
+                .map_err(|e| format!("{}: {}", path, e))
+        }
+        ("restore", Some(restore_matches)) => {
+            let path = restore_matches.value_of("file").unwrap();
+            let input = std::fs::read_to_string(server::resolve_path(path))
+                .map_err(|e| format!("{}: {}", path, e))?;
+            let session: Session =
+                serde_json::from_str(&input).map_err(|e| format!("{}: {}", path, e))?;
+            restore_session(conn, data, &session, !restore_matches.is_present("no-exec"))
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn output(name: &str, active: bool, primary: bool, x: isize) -> Value {
+        json!({
+            "name": name, "active": active, "primary": primary, "current_workspace": null,
+            "rect": {"x": x, "y": 0, "width": 1920, "height": 1080}
+        })
+    }
+
+    #[test]
+    fn test_restore_output() {
+        let saved: Vec<Output> = serde_json::from_value(json!([
+            output("DP-1", true, true, 0),
+            output("HDMI-1", true, false, 1920),
+        ]))
+        .unwrap();
+        let session = Session {
+            outputs: saved
+                .iter()
+                .map(|o| SessionOutput {
+                    name: o.name.clone(),
+                    primary: o.primary,
+                    rect: o.rect.clone(),
+                })
+                .collect(),
+            workspaces: vec![],
+        };
+        // HDMI-1 is now plugged into DP-2, and DP-1 is off.
+        let outputs: Outputs = serde_json::from_value(json!([
+            output("DP-1", false, false, 0),
+            output("DP-2", true, false, 1920),
+            output("eDP-1", true, true, 3840),
+        ]))
+        .unwrap();
+        let restored = |name| restore_output(&session, name, &outputs).map(|o| o.name.as_str());
+        assert_eq!(restored("HDMI-1"), Some("DP-2"));
+        assert_eq!(restored("DP-1"), Some("eDP-1"));
+        assert_eq!(restored("DP-2"), Some("DP-2"));
+        assert_eq!(restored("VGA-1"), None);
+    }
+}
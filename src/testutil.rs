@@ -0,0 +1,82 @@
+//! Fixtures shared by the tests of several modules.
+
+use serde_json::{json, Value};
+
+use i3_ipc::reply::{Node, Outputs, Success, Workspaces};
+
+use std::io;
+
+use crate::ipc::Ipc;
+use crate::urgency::Urgency;
+
+/// Builds the JSON that i3 would send for a node, with every field filled in.
+pub fn node(id: usize, node_type: &str, window: Option<u32>, nodes: Vec<Value>) -> Value {
+    let rect = json!({"x": 0, "y": 0, "width": 0, "height": 0});
+    let focus: Vec<Value> = nodes.iter().map(|n| n["id"].clone()).collect();
+    json!({
+        "id": id, "name": null, "num": null, "type": node_type, "layout": "splith",
+        "orientation": "horizontal", "border": "normal", "current_border_width": 2,
+        "percent": null, "rect": rect, "window_rect": rect, "deco_rect": rect,
+        "geometry": rect, "window": window, "window_properties": null, "window_type": null,
+        "urgent": false, "focused": false, "focus": focus, "floating": "auto_off",
+        "floating_nodes": [], "sticky": false, "nodes": nodes, "fullscreen_mode": 0,
+        "marks": []
+    })
+}
+
+/// Answers every request with a fixed tree and no workspaces or outputs. No window is urgent and
+/// no window has a pid, whatever the state of the machine running the tests.
+pub struct TestIpc(pub Node);
+
+impl Ipc for TestIpc {
+    fn get_tree(&mut self) -> io::Result<Node> {
+        Ok(self.0.clone())
+    }
+
+    fn get_workspaces(&mut self) -> io::Result<Workspaces> {
+        Ok(vec![])
+    }
+
+    fn get_outputs(&mut self) -> io::Result<Outputs> {
+        Ok(vec![])
+    }
+
+    fn run_command(&mut self, _command: &str) -> io::Result<Vec<Success>> {
+        Ok(vec![])
+    }
+
+    fn urgency(&mut self) -> Urgency {
+        Urgency::default()
+    }
+
+    fn window_pid(&mut self, _window: u32) -> Option<u32> {
+        None
+    }
+}
+
+/// A root with a single workspace (2), holding a split container (3) with two windows: 4, which is
+/// focused, and 5.
+pub fn tree() -> Node {
+    let mut tree: Node = serde_json::from_value(node(
+        1,
+        "root",
+        None,
+        vec![node(
+            2,
+            "workspace",
+            None,
+            vec![node(
+                3,
+                "con",
+                None,
+                vec![
+                    node(4, "con", Some(40), vec![]),
+                    node(5, "con", Some(50), vec![]),
+                ],
+            )],
+        )],
+    ))
+    .unwrap();
+    tree.nodes[0].nodes[0].nodes[0].focused = true;
+    tree
+}
@@ -0,0 +1,173 @@
+//! Keeps track of when windows became urgent, so that `urgent=latest` and `urgent=oldest` can be
+//! evaluated the way i3 does. i3 doesn't report urgency timestamps, so `oi3h daemon` and
+//! `oi3h server` record them in a state file as `window::urgent` events arrive.
+//!
+//! Windows that became urgent while nothing was recording are ordered by focus history instead:
+//! they are assumed to have become urgent before any recorded window, and the longer ago a window
+//! was focused, the earlier it is assumed to have become urgent.
+
+use serde::{Deserialize, Serialize};
+
+use i3_ipc::event::{Event, WindowChange};
+use i3_ipc::reply::{Node, NodeType};
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::search;
+use crate::server;
+
+/// Maps container ids to the time (in milliseconds since the epoch) they became urgent.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Urgency(BTreeMap<usize, u64>);
+
+/// The state file, `$XDG_RUNTIME_DIR/oi3h-urgency.json`.
+const STATE: &str = "-urgency.json";
+
+/// Reads the recorded timestamps. A missing or unreadable state file is treated as empty.
+pub fn load() -> Urgency {
+    server::load_state(STATE)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Updates the state file from a window event. Only the first of several recorders sees a window
+/// becoming urgent, so existing timestamps are kept.
+pub fn record(event: &Event) {
+    let event = match event {
+        Event::Window(event) => event,
+        _ => return,
+    };
+    let id = event.container.id;
+    let mut urgency = load();
+    let changed = match event.change {
+        WindowChange::Urgent if event.container.urgent => {
+            !urgency.0.contains_key(&id) && urgency.0.insert(id, now()).is_none()
+        }
+        WindowChange::Urgent | WindowChange::Close => urgency.0.remove(&id).is_some(),
+        _ => false,
+    };
+    if changed {
+        if let Err(e) = server::save_state(STATE, &urgency) {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+/// Maps the id of every node to the positions of the node and its ancestors in their parents'
+/// focus lists. Comparing these puts the most recently focused nodes first.
+fn focus_history(node: &Node, path: Vec<usize>, history: &mut HashMap<usize, Vec<usize>>) {
+    for child in node.nodes.iter().chain(node.floating_nodes.iter()) {
+        let mut child_path = path.clone();
+        child_path.push(
+            node.focus
+                .iter()
+                .position(|&id| id == child.id)
+                .unwrap_or(usize::MAX),
+        );
+        focus_history(child, child_path, history);
+    }
+    history.insert(node.id, path);
+}
+
+/// Returns the urgent windows in `tree`, from the one that became urgent first to the one that
+/// became urgent last.
+pub fn urgent_windows<'a>(tree: &'a Node, urgency: &Urgency) -> Vec<&'a Node> {
+    let mut history = HashMap::new();
+    focus_history(tree, vec![], &mut history);
+
+    let mut urgent = search::i3_tree_find_all(tree, |n| n.window.is_some() && n.urgent);
+    urgent.sort_by_key(|n| {
+        (
+            urgency.0.get(&n.id).copied(),
+            Reverse(history.get(&n.id).cloned()),
+        )
+    });
+    urgent
+}
+
+/// Returns the urgent workspaces in `tree`, starting with the one whose window became urgent
+/// last.
+pub fn urgent_workspaces<'a>(tree: &'a Node, urgency: &Urgency) -> Vec<&'a Node> {
+    let workspaces = search::i3_tree_find_all(tree, |n| n.node_type == NodeType::Workspace);
+    let mut urgent: Vec<&Node> = vec![];
+    for window in urgent_windows(tree, urgency).into_iter().rev() {
+        let workspace = workspaces
+            .iter()
+            .find(|ws| search::i3_tree_find_first(ws, |n| n.id == window.id).is_some());
+        if let Some(&workspace) = workspace {
+            if !urgent.iter().any(|ws| ws.id == workspace.id) {
+                urgent.push(workspace);
+            }
+        }
+    }
+    // Workspaces can be marked urgent without any of their windows being urgent.
+    for workspace in workspaces {
+        if workspace.urgent && !urgent.iter().any(|ws| ws.id == workspace.id) {
+            urgent.push(workspace);
+        }
+    }
+    urgent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{node, tree};
+
+    /// Two workspaces (2 and 5) with a window each (4 and 7), and an empty workspace (8).
+    fn workspaces() -> Node {
+        let output = node(
+            10,
+            "output",
+            None,
+            vec![
+                node(2, "workspace", None, vec![node(4, "con", Some(40), vec![])]),
+                node(5, "workspace", None, vec![node(7, "con", Some(70), vec![])]),
+                node(8, "workspace", None, vec![]),
+            ],
+        );
+        serde_json::from_value(node(1, "root", None, vec![output])).unwrap()
+    }
+
+    #[test]
+    fn test_urgent_windows() {
+        let mut tree = tree();
+        let ids = |windows: Vec<&Node>| windows.iter().map(|n| n.id).collect::<Vec<_>>();
+        assert!(urgent_windows(&tree, &Urgency::default()).is_empty());
+
+        // Window 4 was focused more recently than window 5.
+        tree.nodes[0].nodes[0].nodes[0].urgent = true;
+        tree.nodes[0].nodes[0].nodes[1].urgent = true;
+        assert_eq!(ids(urgent_windows(&tree, &Urgency::default())), vec![5, 4]);
+
+        let mut urgency = Urgency::default();
+        urgency.0.insert(5, 2000);
+        assert_eq!(ids(urgent_windows(&tree, &urgency)), vec![4, 5]);
+        urgency.0.insert(4, 3000);
+        assert_eq!(ids(urgent_windows(&tree, &urgency)), vec![5, 4]);
+        assert_eq!(ids(urgent_workspaces(&tree, &urgency)), vec![2]);
+    }
+
+    #[test]
+    fn test_urgent_workspaces() {
+        let mut tree = workspaces();
+        let ids = |workspaces: Vec<&Node>| workspaces.iter().map(|n| n.id).collect::<Vec<_>>();
+        tree.nodes[0].nodes[0].nodes[0].urgent = true;
+        tree.nodes[0].nodes[1].nodes[0].urgent = true;
+        // i3 can mark a workspace urgent without any of its windows being urgent.
+        tree.nodes[0].nodes[2].urgent = true;
+
+        let mut urgency = Urgency::default();
+        urgency.0.insert(4, 2000);
+        urgency.0.insert(7, 1000);
+        assert_eq!(ids(urgent_workspaces(&tree, &urgency)), vec![2, 5, 8]);
+        urgency.0.insert(7, 3000);
+        assert_eq!(ids(urgent_workspaces(&tree, &urgency)), vec![5, 2, 8]);
+    }
+}
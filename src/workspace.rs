@@ -0,0 +1,308 @@
+//! Workspace operations that i3 doesn't provide directly. All operations are scoped to the outputs
+//! selected by the `output=` criteria.
+
+use regex::Regex;
+
+use i3_ipc::reply::{Node, Workspace};
+
+use crate::criteria::{self, Match};
+use crate::i3cache::I3Cache;
+use crate::ipc::{self, Ipc};
+use crate::search;
+
+/// Temporary name used while swapping two workspaces. Names starting with `__` are reserved by i3.
+const SWAP_TEMP_NAME: &str = "oi3h-swap";
+
+/// Replaces the number at the start of a workspace name, keeping the rest of the name intact, e.g.
+/// `renumber("3: web", 1) == "1: web"`.
+fn renumber(name: &str, num: i32) -> String {
+    format!(
+        "{}{}",
+        num,
+        name.trim_start_matches(|c: char| c.is_ascii_digit())
+    )
+}
+
+/// Returns the lowest workspace number that isn't in use, starting from the lowest number in
+/// `scoped`. This keeps outputs that use their own number ranges (e.g. 1-10 and 11-20) separate.
+fn next_free_num(used: &[i32], scoped: &[i32]) -> i32 {
+    let start = scoped
+        .iter()
+        .filter(|&&n| n > 0)
+        .min()
+        .cloned()
+        .unwrap_or(1);
+    (start..).find(|n| !used.contains(n)).unwrap()
+}
+
+/// Returns `(old, new)` pairs that renumber `scoped` contiguously, starting from its lowest
+/// number and skipping any numbers in `used_elsewhere`. `scoped` must be sorted.
+fn compact_plan(scoped: &[i32], used_elsewhere: &[i32]) -> Vec<(i32, i32)> {
+    let mut plan = vec![];
+    let mut next = match scoped.first() {
+        Some(&first) => first,
+        None => return plan,
+    };
+    for &num in scoped.iter() {
+        while used_elsewhere.contains(&next) {
+            next += 1;
+        }
+        if next != num {
+            plan.push((num, next));
+        }
+        next += 1;
+    }
+    plan
+}
+
+fn numbered_on<'a>(workspaces: &'a [Workspace], outputs: &[String]) -> Vec<&'a Workspace> {
+    let mut numbered: Vec<&Workspace> = workspaces
+        .iter()
+        .filter(|w| w.num >= 0 && outputs.contains(&w.output))
+        .collect();
+    numbered.sort_by_key(|w| w.num);
+    numbered
+}
+
+/// Finds a workspace by exact name, or by number if no workspace has that name.
+fn find_workspace<'a>(
+    workspaces: &'a [Workspace],
+    outputs: &[String],
+    query: &str,
+) -> Result<&'a Workspace, String> {
+    let scoped = || workspaces.iter().filter(|w| outputs.contains(&w.output));
+    scoped()
+        .find(|w| w.name == query)
+        .or_else(|| {
+            query
+                .parse::<i32>()
+                .ok()
+                .and_then(|num| scoped().find(|w| w.num == num))
+        })
+        .ok_or(format!(
+            "No workspace '{}' on the selected output(s)",
+            query
+        ))
+}
+
+/// Returns the workspace that follows `current` in `ordered`, wrapping around, for which
+/// `candidate` returns true. If `current` isn't in `ordered`, the search starts from the beginning
+/// (or the end, if `reverse` is set).
+fn cycle_target<'a, P>(
+    ordered: &[&'a Node],
+    current: &Node,
+    reverse: bool,
+    mut candidate: P,
+) -> Option<&'a Node>
+where
+    P: FnMut(&Node) -> bool,
+{
+    let len = ordered.len();
+    let pos = ordered.iter().position(|n| n.id == current.id);
+    (1..=len)
+        .map(|step| match (pos, reverse) {
+            (Some(p), false) => (p + step) % len,
+            (Some(p), true) => (p + len - step) % len,
+            (None, false) => step - 1,
+            (None, true) => len - step,
+        })
+        .map(|i| ordered[i])
+        .find(|n| n.id != current.id && candidate(n))
+}
+
+fn cycle(
+    matches: &clap::ArgMatches,
+    criteria: &[Match],
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    let reverse = match matches.value_of("cycle").unwrap() {
+        "next" => false,
+        "prev" => true,
+        _ => unreachable!(), // already validated by clap
+    };
+    let class = matches
+        .value_of("class")
+        .map(Regex::new)
+        .transpose()
+        .map_err(|e| format!("class: {}", e))?;
+    let skip_empty = matches.is_present("skip-empty");
+
+    let current = data.focused_workspace(conn)?;
+    let ordered = criteria::all_workspaces(criteria::matched_outputs(conn, data, criteria)?);
+    let selected = criteria::matched_workspaces(conn, data, criteria)?;
+
+    let target = cycle_target(&ordered.0, current, reverse, |ws| {
+        if !selected.0.iter().any(|s| s.id == ws.id) {
+            return false;
+        }
+        let mut windows = search::i3_tree_find_all(ws, |n| n.window.is_some());
+        if let Some(class) = &class {
+            windows.retain(|n| {
+                n.window_properties
+                    .as_ref()
+                    .and_then(|p| p.class.as_deref())
+                    .map_or(false, |c| class.is_match(c))
+            });
+            !windows.is_empty()
+        } else {
+            !(skip_empty && windows.is_empty())
+        }
+    });
+
+    match target.and_then(|t| t.name.as_deref()) {
+        Some(name) => ipc::run_command(conn, &format!("workspace {}", criteria::i3_quote(name))),
+        None => Ok(()),
+    }
+}
+
+fn rename(conn: &mut dyn Ipc, from: &str, to: &str) -> Result<(), String> {
+    ipc::run_command(
+        conn,
+        &format!(
+            "rename workspace {} to {}",
+            criteria::i3_quote(from),
+            criteria::i3_quote(to)
+        ),
+    )
+}
+
+pub fn workspace_subcmd(
+    matches: &clap::ArgMatches,
+    criteria: &[Match],
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    let workspaces = data.workspaces(conn).map_err(|e| format!("{}", e))?;
+    let outputs: Vec<String> = criteria::matched_outputs(conn, data, criteria)?
+        .0
+        .iter()
+        .filter_map(|o| o.name.clone())
+        .collect();
+    if outputs.is_empty() {
+        return Err("No outputs match the given criteria".to_string());
+    }
+
+    let focused = workspaces
+        .iter()
+        .find(|w| w.focused)
+        .ok_or("Unable to find focused workspace")?;
+    // Prefer the focused output if it was selected, otherwise use the first selected output.
+    let target_output = outputs
+        .iter()
+        .find(|&o| o == &focused.output)
+        .unwrap_or(&outputs[0]);
+
+    let used: Vec<i32> = workspaces.iter().map(|w| w.num).collect();
+    let scoped: Vec<i32> = workspaces
+        .iter()
+        .filter(|w| &w.output == target_output)
+        .map(|w| w.num)
+        .collect();
+
+    if matches.is_present("cycle") {
+        cycle(matches, criteria, conn, data)
+    } else if matches.is_present("next-free") {
+        let num = next_free_num(&used, &scoped);
+        ipc::run_command(
+            conn,
+            &format!(
+                "focus output {}; workspace number {}",
+                criteria::i3_quote(target_output),
+                num
+            ),
+        )
+    } else if matches.is_present("move-to-next-free") {
+        let num = next_free_num(&used, &scoped);
+        let mut command = format!(
+            "move container to workspace number {}; workspace number {}",
+            num, num
+        );
+        if target_output != &focused.output {
+            command += &format!(
+                "; move workspace to output {}",
+                criteria::i3_quote(target_output)
+            );
+        }
+        if !matches.is_present("follow") {
+            command += &format!("; workspace {}", criteria::i3_quote(&focused.name));
+        }
+        ipc::run_command(conn, &command)
+    } else if matches.is_present("compact") {
+        // Workspace numbers are global, so keep track of every rename to avoid collisions when
+        // compacting more than one output.
+        let mut current: Vec<(i32, String, String)> = workspaces
+            .iter()
+            .map(|w| (w.num, w.name.clone(), w.output.clone()))
+            .collect();
+        for output in outputs.iter() {
+            let scoped: Vec<i32> = numbered_on(&workspaces, std::slice::from_ref(output))
+                .iter()
+                .map(|w| w.num)
+                .collect();
+            let used_elsewhere: Vec<i32> = current
+                .iter()
+                .filter(|c| &c.2 != output)
+                .map(|c| c.0)
+                .collect();
+            for (old, new) in compact_plan(&scoped, &used_elsewhere) {
+                let entry = current
+                    .iter_mut()
+                    .find(|c| c.0 == old && &c.2 == output)
+                    .unwrap();
+                let new_name = renumber(&entry.1, new);
+                rename(conn, &entry.1, &new_name)?;
+                entry.0 = new;
+                entry.1 = new_name;
+            }
+        }
+        Ok(())
+    } else if let Some(mut swap) = matches.values_of("swap") {
+        let a = find_workspace(&workspaces, &outputs, swap.next().unwrap())?;
+        let b = find_workspace(&workspaces, &outputs, swap.next().unwrap())?;
+        if a.name == b.name {
+            return Ok(());
+        }
+        // Swap numbers if both workspaces have one, otherwise swap the whole name.
+        let (new_a, new_b) = if a.num >= 0 && b.num >= 0 {
+            (renumber(&a.name, b.num), renumber(&b.name, a.num))
+        } else {
+            (b.name.clone(), a.name.clone())
+        };
+        rename(conn, &a.name, SWAP_TEMP_NAME)?;
+        rename(conn, &b.name, &new_b)?;
+        rename(conn, SWAP_TEMP_NAME, &new_a)
+    } else {
+        outln!("{}", next_free_num(&used, &scoped));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renumber() {
+        assert_eq!(renumber("3", 1), "1");
+        assert_eq!(renumber("3: web", 1), "1: web");
+        assert_eq!(renumber("12:mail", 4), "4:mail");
+    }
+
+    #[test]
+    fn test_next_free_num() {
+        assert_eq!(next_free_num(&[], &[]), 1);
+        assert_eq!(next_free_num(&[1, 2, 4], &[1, 2, 4]), 3);
+        assert_eq!(next_free_num(&[1, 2, 11, 12], &[11, 12]), 13);
+        assert_eq!(next_free_num(&[-1, 2], &[-1]), 1);
+    }
+
+    #[test]
+    fn test_compact_plan() {
+        assert_eq!(compact_plan(&[], &[]), vec![]);
+        assert_eq!(compact_plan(&[1, 2, 3], &[]), vec![]);
+        assert_eq!(compact_plan(&[1, 3, 7], &[]), vec![(3, 2), (7, 3)]);
+        assert_eq!(compact_plan(&[1, 4, 7], &[2, 3]), vec![(7, 5)]);
+        assert_eq!(compact_plan(&[11, 15], &[1, 2]), vec![(15, 12)]);
+    }
+}
@@ -0,0 +1,159 @@
+//! Renames workspaces to `<num>: <icons>`, where the icons are derived from the classes of the
+//! windows on each workspace.
+
+use i3_ipc::event::{Event, Subscribe, WindowChange};
+
+use crate::config::{self, Config};
+use crate::criteria::{self, ClassMap};
+use crate::i3cache::{self, I3Cache};
+use crate::ipc::{self, Ipc};
+use crate::search;
+
+/// Options that control how workspace names are built.
+pub struct NameOptions {
+    pub icons: ClassMap,
+    /// Icon used for classes that aren't in the map. If `None`, the class name is used instead.
+    pub default_icon: Option<String>,
+    /// Only show one icon per class.
+    pub unique: bool,
+}
+
+fn workspace_name(num: i32, classes: &[&str], options: &NameOptions) -> String {
+    let mut icons: Vec<&str> = vec![];
+    for class in classes.iter() {
+        let icon = options
+            .icons
+            .get(class)
+            .or(options.default_icon.as_deref())
+            .unwrap_or(class);
+        if !(options.unique && icons.contains(&icon)) {
+            icons.push(icon);
+        }
+    }
+
+    if icons.is_empty() {
+        num.to_string()
+    } else {
+        format!("{}: {}", num, icons.join(" "))
+    }
+}
+
+/// Renames every numbered workspace whose name doesn't match its current set of windows.
+/// Workspaces without a number (e.g. `workspace "web"`) are left alone.
+pub fn rename_workspaces(
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+    options: &NameOptions,
+) -> Result<(), String> {
+    let workspaces = data.workspaces(conn).map_err(|e| format!("{}", e))?;
+    let workspace_nodes = criteria::all_workspaces(criteria::all_outputs(conn, data)?);
+
+    let mut commands: Vec<String> = vec![];
+    for workspace in workspaces.iter().filter(|w| w.num >= 0) {
+        let node = match workspace_nodes
+            .0
+            .iter()
+            .find(|n| n.name.as_deref() == Some(workspace.name.as_str()))
+        {
+            Some(node) => node,
+            None => continue,
+        };
+
+        let windows = search::i3_tree_find_all(node, |n| n.window.is_some());
+        let classes: Vec<&str> = windows
+            .iter()
+            .filter_map(|n| n.window_properties.as_ref())
+            .filter_map(|p| p.class.as_deref())
+            .collect();
+
+        let new_name = workspace_name(workspace.num, &classes, options);
+        if new_name != workspace.name {
+            commands.push(format!(
+                "rename workspace {} to {}",
+                criteria::i3_quote(&workspace.name),
+                criteria::i3_quote(&new_name)
+            ));
+        }
+    }
+
+    for command in commands.iter() {
+        ipc::run_command(conn, command)?;
+    }
+    Ok(())
+}
+
+pub fn workspace_names_subcmd(
+    matches: &clap::ArgMatches,
+    config: &Config,
+    conn: &mut dyn Ipc,
+    data: &I3Cache,
+) -> Result<(), String> {
+    let options = NameOptions {
+        icons: match matches.value_of("icons") {
+            Some(icons) => match config::reference(icons) {
+                Some(name) => config.icons(name)?.clone(),
+                None => criteria::read_class_map(icons)?,
+            },
+            None => config.default_icons().cloned().unwrap_or_default(),
+        },
+        default_icon: matches.value_of("default-icon").map(|i| i.to_string()),
+        unique: matches.is_present("unique"),
+    };
+
+    rename_workspaces(conn, data, &options)?;
+    if matches.is_present("once") {
+        return Ok(());
+    }
+
+    let resync = i3cache::resync_interval(matches)?;
+    let mut data = I3Cache::new();
+    let events = i3cache::listen(&[Subscribe::Window, Subscribe::Workspace, Subscribe::Output])?;
+    while let Some(event) = data.next_event(conn, &events, resync) {
+        let event = event.map_err(|e| format!("{}", e))?;
+        data.update(&event, resync);
+        if matches.is_present("check") {
+            i3cache::report_drift(conn, &data);
+        }
+        match event {
+            Event::Window(ev) => match ev.change {
+                WindowChange::New | WindowChange::Close | WindowChange::Move => {}
+                _ => continue,
+            },
+            Event::Workspace(_) => {}
+            _ => continue,
+        }
+
+        rename_workspaces(conn, &data, &options)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workspace_name() {
+        let options = NameOptions {
+            icons: criteria::parse_class_map("firefox = F\nterm = T").unwrap(),
+            default_icon: None,
+            unique: false,
+        };
+        assert_eq!(workspace_name(1, &[], &options), "1");
+        assert_eq!(
+            workspace_name(2, &["firefox", "term", "term"], &options),
+            "2: F T T"
+        );
+        assert_eq!(workspace_name(3, &["mpv"], &options), "3: mpv");
+
+        let options = NameOptions {
+            default_icon: Some("?".to_string()),
+            unique: true,
+            ..options
+        };
+        assert_eq!(
+            workspace_name(2, &["firefox", "term", "term", "mpv"], &options),
+            "2: F T ?"
+        );
+    }
+}